@@ -0,0 +1,141 @@
+//! Who may do what with a repository.
+//!
+//! Grants are plain text files with one `<user> <role>` per line, where the user
+//! `*` stands for everyone, including anonymous visitors. Entity-wide grants live
+//! in `<data_dir>/access/<entity>.acl`, and collaborators of a single repository
+//! in `<data_dir>/access/<entity>/<repo>.acl`. Users always own the entity of the same
+//! name, and without any grants everyone can read.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use axum::http::StatusCode;
+
+use crate::git::Service;
+use crate::resolve::RepoName;
+
+/// Web requests don't log in, so they're checked as anonymous.
+pub(crate) const ANONYMOUS: Option<&str> = None;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    None,
+    Read,
+    Write,
+    Admin,
+}
+
+impl Role {
+    fn parse(role: &str) -> Option<Self> {
+        match role {
+            "none" => Some(Role::None),
+            "read" => Some(Role::Read),
+            "write" => Some(Role::Write),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    /// The role it takes to use a git service.
+    pub(crate) fn for_service(service: Service) -> Self {
+        match service {
+            Service::UploadPack | Service::UploadArchive => Role::Read,
+            Service::ReceivePack => Role::Write,
+        }
+    }
+}
+
+/// The grants of a single access file.
+#[derive(Default)]
+struct Grants {
+    users: HashMap<String, Role>,
+    everyone: Option<Role>,
+}
+
+impl Grants {
+    async fn read(path: &Path) -> Self {
+        let mut grants = Grants::default();
+        let Ok(contents) = tokio::fs::read_to_string(path).await else {
+            return grants;
+        };
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, role)) = line
+                .split_once(char::is_whitespace)
+                .and_then(|(user, role)| Some((user, Role::parse(role.trim())?)))
+            else {
+                eprintln!(
+                    "WARNING: invalid grant \"{line}\" in {}",
+                    path.to_string_lossy()
+                );
+                continue;
+            };
+            match user {
+                "*" => grants.everyone = Some(role),
+                user => {
+                    grants.users.insert(user.to_owned(), role);
+                }
+            }
+        }
+        grants
+    }
+
+    fn user(&self, user: Option<&str>) -> Role {
+        user.and_then(|user| self.users.get(user))
+            .copied()
+            .unwrap_or(Role::None)
+    }
+}
+
+fn entity_acl(data_dir: &Path, entity: &str) -> PathBuf {
+    data_dir.join(format!("access/{entity}.acl"))
+}
+
+/// The role `user` (or an anonymous visitor) has for everything in `entity`.
+pub(crate) async fn entity_role(data_dir: &Path, user: Option<&str>, entity: &str) -> Role {
+    if user == Some(entity) {
+        return Role::Admin;
+    }
+    let grants = Grants::read(&entity_acl(data_dir, entity)).await;
+    grants.user(user).max(grants.everyone.unwrap_or(Role::Read))
+}
+
+/// The role `user` (or an anonymous visitor) has for `entity/repo`. Collaborators
+/// add to what the entity grants, while `*` overrides the entity's `*`.
+pub(crate) async fn repo_role(
+    data_dir: &Path,
+    user: Option<&str>,
+    entity: &str,
+    repo: &str,
+) -> Role {
+    if user == Some(entity) {
+        return Role::Admin;
+    }
+    let entity_grants = Grants::read(&entity_acl(data_dir, entity)).await;
+    let repo_grants = Grants::read(&data_dir.join(format!("access/{entity}/{repo}.acl"))).await;
+    let everyone = repo_grants
+        .everyone
+        .or(entity_grants.everyone)
+        .unwrap_or(Role::Read);
+    entity_grants
+        .user(user)
+        .max(repo_grants.user(user))
+        .max(everyone)
+}
+
+/// Make sure `user` holds at least `role` for `name`. Repositories someone can't
+/// read don't exist as far as they're concerned.
+pub(crate) async fn require(
+    data_dir: &Path,
+    user: Option<&str>,
+    name: &RepoName,
+    role: Role,
+) -> Result<(), StatusCode> {
+    match repo_role(data_dir, user, &name.entity, &name.repo).await {
+        granted if granted >= role => Ok(()),
+        granted if granted >= Role::Read => Err(StatusCode::FORBIDDEN),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{get_entries, Args};
+use axum::http::StatusCode;
+
+use crate::access::{self, Role, ANONYMOUS};
+use crate::{get_entries, resolve, Args};
 
 #[derive(serde::Serialize)]
 pub(crate) struct Entities {
@@ -21,28 +24,34 @@ pub(crate) struct Repo {
 }
 
 pub(crate) async fn entities(args: &Args) -> Entities {
-    let entities: Vec<Entity> = get_entries(&args.data_dir.join("repositories/"))
-        .await
-        .into_iter()
-        .map(|i| Entity {
-            name: i.to_str().unwrap().to_owned(),
-        })
-        .collect();
+    let mut entities = vec![];
+    for i in get_entries(&args.data_dir.join("repositories/")).await {
+        let name = i.to_str().unwrap().to_owned();
+        if access::entity_role(&args.data_dir, ANONYMOUS, &name).await >= Role::Read {
+            entities.push(Entity { name });
+        }
+    }
     Entities { entities }
 }
 
 impl Entity {
-    pub(crate) async fn repos(args: &Args, entity_name: &str) -> Repos {
-        let repo_entry_links =
-            get_entries(&args.data_dir.join(format!("repositories/{entity_name}")))
-                .await
-                .into_iter()
-                .map(|i| Repo {
-                    name: i.to_str().unwrap().to_owned(),
-                })
-                .collect();
-        Repos {
-            repos: repo_entry_links,
+    pub(crate) async fn repos(args: &Args, entity_name: &str) -> Result<Repos, StatusCode> {
+        let entity_path = resolve::entity_path(&args.data_dir, entity_name).await?;
+        if access::entity_role(&args.data_dir, ANONYMOUS, entity_name).await < Role::Read {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let mut repo_entry_links = vec![];
+        for i in get_entries(&entity_path).await {
+            // repositories kept as `repo.git` go by `repo`, like everywhere else.
+            let name = i.to_str().unwrap();
+            let name = name.strip_suffix(".git").unwrap_or(name).to_owned();
+            if access::repo_role(&args.data_dir, ANONYMOUS, entity_name, &name).await >= Role::Read
+            {
+                repo_entry_links.push(Repo { name });
+            }
         }
+        Ok(Repos {
+            repos: repo_entry_links,
+        })
     }
 }
@@ -1,30 +1,49 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use axum::{
+    body::Body,
     http::StatusCode,
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Response},
 };
+use futures::StreamExt;
 use tera::{Context, Tera};
 
 use crate::{
-    repositories::{CommitLog, CommitLogReq},
+    repositories::{
+        split_rev, Blame, Blob, Branches, CommitDetail, CommitLog, CommitLogReq, Compare, Readme,
+        Tags, Tree, TreeReq,
+    },
     Args,
 };
 
 const HOSTNAME: &str = "localhost"; // todo: fix - make this actually a proper config'd item.
 const PORT: i32 = 4000; // todo: fix - make this actually a proper config'd item.
 
+/// Where the rows of `blame.html` go, which are streamed in as they're blamed.
+const BLAME_HUNKS: &str = "<!-- hunks -->";
+
+#[derive(serde::Deserialize)]
+pub struct BlobReq {
+    /// Show the source of files that would otherwise be rendered, like markdown.
+    #[serde(default)]
+    pub plain: bool,
+}
+
 pub struct Frontend {
     args: Arc<Args>,
-    tera: Tera,
+    tera: Arc<Tera>,
 }
 
 impl Frontend {
     pub fn new(args: Arc<Args>) -> Self {
+        let mut tera = Tera::new("templates/**/*.html")
+            .expect("Failed to create Tera instance from templates/");
+        tera.register_filter("relative", relative);
         Self {
             args,
-            tera: Tera::new("templates/**/*.html")
-                .expect("Failed to create Tera instance from templates/"),
+            tera: Arc::new(tera),
         }
     }
     pub async fn index(&self) -> impl IntoResponse {
@@ -38,12 +57,12 @@ impl Frontend {
         c.insert("entities", &entities.entities);
         Html(self.tera.render("entities.html", &c).unwrap())
     }
-    pub async fn repositories(&self, name: &str) -> Html<String> {
+    pub async fn repositories(&self, name: &str) -> Result<Html<String>, StatusCode> {
         let mut c = Context::new();
-        let repos = crate::entities::Entity::repos(&self.args, name).await;
+        let repos = crate::entities::Entity::repos(&self.args, name).await?;
         c.insert("repositories", &repos.repos);
         c.insert("entity_name", name);
-        Html(self.tera.render("repositories.html", &c).unwrap())
+        Ok(Html(self.tera.render("repositories.html", &c).unwrap()))
     }
     pub async fn repository(
         &self,
@@ -55,9 +74,178 @@ impl Frontend {
         c.insert("entity_name", entity);
         c.insert("repository_name", repo);
         c.insert("commit_id", &req.rev);
-        c.insert("increment", &req.increment);
-        let commits = CommitLog::commit_log(&self.args, entity, repo, req).await;
-        c.insert("commits", &commits?.commits);
+        c.insert("per_page", &req.per_page);
+        c.insert("filter", &req.filter);
+        let log = CommitLog::commit_log(&self.args, entity, repo, req).await?;
+        c.insert("commits", &log.commits);
+        c.insert("next", &log.next);
+        c.insert("prev", &log.prev);
+        let readme = Readme::readme(&self.args, entity, repo, None, "").await?;
+        c.insert("readme", &readme);
         Ok(Html(self.tera.render("repository.html", &c).unwrap()))
     }
+    pub async fn tree(
+        &self,
+        entity: &str,
+        repo: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Html<String>, StatusCode> {
+        let (rev, path) = split_rev(&self.args, entity, repo, rev, path).await?;
+        let (rev, path) = (rev.as_str(), path.as_str());
+        let req = TreeReq {
+            rev: Some(rev.to_owned()),
+            path: Some(path.to_owned()),
+        };
+        let tree = Tree::tree(&self.args, entity, repo, &req).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("rev", rev);
+        c.insert("breadcrumbs", &breadcrumbs(&tree.path));
+        c.insert("tree", &tree);
+        let readme = Readme::readme(&self.args, entity, repo, Some(rev), path).await?;
+        c.insert("readme", &readme);
+        Ok(Html(self.tera.render("tree.html", &c).unwrap()))
+    }
+    pub async fn blob(
+        &self,
+        entity: &str,
+        repo: &str,
+        rev: &str,
+        path: &str,
+        req: &BlobReq,
+    ) -> Result<Html<String>, StatusCode> {
+        let (rev, path) = split_rev(&self.args, entity, repo, rev, path).await?;
+        let (rev, path) = (rev.as_str(), path.as_str());
+        let blob = Blob::blob(&self.args, entity, repo, rev, path).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("rev", rev);
+        c.insert("breadcrumbs", &breadcrumbs(&blob.path));
+        c.insert("blob", &blob);
+        c.insert("plain", &req.plain);
+        Ok(Html(self.tera.render("blob.html", &c).unwrap()))
+    }
+    pub async fn blame(
+        &self,
+        entity: &str,
+        repo: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Response, StatusCode> {
+        let (rev, path) = split_rev(&self.args, entity, repo, rev, path).await?;
+        let (rev, path) = (rev.as_str(), path.as_str());
+        let blame = Blame::blame(&self.args, entity, repo, rev, path).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("rev", rev);
+        c.insert("breadcrumbs", &breadcrumbs(&blame.path));
+        c.insert("blame", &blame);
+        let page = self.tera.render("blame.html", &c).unwrap();
+        let (head, tail) = page.split_once(BLAME_HUNKS).unwrap();
+        let (head, tail) = (head.to_owned(), tail.to_owned());
+
+        let tera = self.tera.clone();
+        let hunks = tokio_stream::wrappers::ReceiverStream::new(blame.hunks).map(move |hunk| {
+            c.insert("hunk", &hunk);
+            tera.render("blame_hunk.html", &c).unwrap()
+        });
+        let body = futures::stream::once(async { head })
+            .chain(hunks)
+            .chain(futures::stream::once(async { tail }))
+            .map(Ok::<_, std::convert::Infallible>);
+        Ok(Html(Body::from_stream(body)).into_response())
+    }
+    pub async fn commit(
+        &self,
+        entity: &str,
+        repo: &str,
+        rev: &str,
+    ) -> Result<Html<String>, StatusCode> {
+        let commit = CommitDetail::commit(&self.args, entity, repo, rev).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("commit", &commit);
+        Ok(Html(self.tera.render("commit.html", &c).unwrap()))
+    }
+    pub async fn compare(
+        &self,
+        entity: &str,
+        repo: &str,
+        spec: &str,
+    ) -> Result<Html<String>, StatusCode> {
+        let compare = Compare::compare(&self.args, entity, repo, spec).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("spec", spec);
+        c.insert("compare", &compare);
+        Ok(Html(self.tera.render("compare.html", &c).unwrap()))
+    }
+    pub async fn branches(&self, entity: &str, repo: &str) -> Result<Html<String>, StatusCode> {
+        let branches = Branches::branches(&self.args, entity, repo).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("default_branch", &branches.default_branch);
+        c.insert("branches", &branches.branches);
+        Ok(Html(self.tera.render("branches.html", &c).unwrap()))
+    }
+    pub async fn tags(&self, entity: &str, repo: &str) -> Result<Html<String>, StatusCode> {
+        let tags = Tags::tags(&self.args, entity, repo).await?;
+        let mut c = Context::new();
+        c.insert("entity_name", entity);
+        c.insert("repository_name", repo);
+        c.insert("default_branch", &tags.default_branch);
+        c.insert("tags", &tags.tags);
+        Ok(Html(self.tera.render("tags.html", &c).unwrap()))
+    }
+}
+
+/// Every directory leading up to `path` as `(name, path)`, for navigating back up.
+fn breadcrumbs(path: &str) -> Vec<(&str, &str)> {
+    path.match_indices('/')
+        .map(|(i, _)| i)
+        .chain((!path.is_empty()).then_some(path.len()))
+        .map(|end| {
+            let start = path[..end].rfind('/').map_or(0, |i| i + 1);
+            (&path[start..end], &path[..end])
+        })
+        .collect()
+}
+
+/// `{{ time | relative }}`: seconds since the unix epoch as something like
+/// `3 days ago`.
+fn relative(value: &tera::Value, _: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    const UNITS: &[(&str, i64)] = &[
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("week", 7 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+    ];
+    let time = tera::from_value::<i64>(value.clone())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    let ago = now - time;
+    let relative = match UNITS.iter().find(|(_, secs)| ago.abs() >= *secs) {
+        None => "just now".to_owned(),
+        Some((unit, secs)) => {
+            let n = ago.abs() / secs;
+            let s = if n == 1 { "" } else { "s" };
+            // clocks being what they are, commits can come from the future.
+            if ago < 0 {
+                format!("in {n} {unit}{s}")
+            } else {
+                format!("{n} {unit}{s} ago")
+            }
+        }
+    };
+    Ok(tera::Value::String(relative))
 }
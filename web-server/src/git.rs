@@ -0,0 +1,122 @@
+//! The git services (upload-pack, receive-pack and upload-archive), independent
+//! of the transport that carries them. A transport only has to open the
+//! repository, describe the request in a [`ServiceContext`] and hand over a
+//! reader and a writer.
+
+use std::path::PathBuf;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{TokioAsyncReadCompatExt as _, TokioAsyncWriteCompatExt as _};
+
+use crate::repositories::ArchiveFormat;
+
+mod advertise;
+mod protocol_v2;
+mod receive_pack;
+mod upload_archive;
+mod upload_pack;
+
+#[cfg(test)]
+mod tests;
+
+pub(crate) use advertise::Service;
+
+/// Everything about a request a git service needs to know, besides the bytes on
+/// the wire.
+pub(crate) struct ServiceContext {
+    pub(crate) repo_path: PathBuf,
+    pub(crate) repo: gix::ThreadSafeRepository,
+    /// The user the transport authenticated, if any.
+    pub(crate) identity: Option<String>,
+    /// Parameters the client sent through `GIT_PROTOCOL` or the `Git-Protocol`
+    /// header, like `version=2`.
+    pub(crate) protocol: Vec<String>,
+    /// Whether every request stands on its own, as in git's `--stateless-rpc`
+    /// mode used by the smart HTTP transport.
+    pub(crate) stateless: bool,
+    /// The formats upload-archive may make archives in.
+    pub(crate) archive_formats: Vec<ArchiveFormat>,
+}
+
+impl ServiceContext {
+    /// Whether `service` is spoken in protocol v2. Only upload-pack knows it.
+    pub(crate) fn protocol_v2(&self, service: Service) -> bool {
+        service == Service::UploadPack && self.protocol.iter().any(|param| param == "version=2")
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error {
+    FailedToArchive(std::io::Error),
+    FailedToListRefs(Box<dyn std::error::Error + Send + Sync>),
+    FailedToPack(git2::Error),
+    InvalidArchiveRequest(String),
+    InvalidCommitter,
+    InvalidPacketLine,
+    InvalidRefUpdate(gix::bstr::BString),
+    InvalidWant(gix::bstr::BString),
+    Io(std::io::Error),
+    UnknownCommand,
+}
+
+impl Error {
+    fn failed_to_list_refs(e: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self::FailedToListRefs(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Write what a client gets to see first: the refs and capabilities of
+/// `service`, or just the capabilities in protocol v2. upload-archive starts
+/// off with the client talking instead.
+pub(crate) async fn advertise<W>(
+    ctx: &ServiceContext,
+    service: Service,
+    writer: W,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = writer.compat_write();
+    if service == Service::UploadArchive {
+        return Ok(());
+    }
+    if ctx.protocol_v2(service) {
+        protocol_v2::advertise_capabilities(&mut writer).await?;
+    } else {
+        let refs = advertise::list_refs(&ctx.repo.to_thread_local());
+        let refs = advertise::tell_client(refs, &mut writer).await?;
+        advertise::reference_discovery(&mut writer, &refs, service).await?;
+    }
+    Ok(())
+}
+
+/// Serve `service` once the client has seen the advertisement.
+pub(crate) async fn serve<R, W>(
+    ctx: &ServiceContext,
+    service: Service,
+    reader: R,
+    writer: W,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin,
+{
+    let writer = writer.compat_write();
+    match service {
+        Service::UploadPack if ctx.protocol_v2(service) => {
+            protocol_v2::serve(reader.compat(), writer, ctx).await
+        }
+        Service::UploadPack => upload_pack::upload_pack(reader.compat(), writer, ctx).await,
+        Service::ReceivePack => receive_pack::receive_pack(reader, writer, ctx).await,
+        Service::UploadArchive => {
+            upload_archive::upload_archive(reader.compat(), writer, ctx).await
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+use futures::{AsyncWrite, AsyncWriteExt};
+use gix::bstr::BString;
+use gix::ObjectId;
+
+use super::Error;
+
+pub(super) const AGENT: &str = concat!("agent=code-forge/", env!("CARGO_PKG_VERSION"));
+
+/// The service a ref advertisement is made for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Service {
+    UploadPack,
+    ReceivePack,
+    UploadArchive,
+}
+
+impl Service {
+    /// The service a client asks for by its command name, like `git-upload-pack`.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "git-upload-pack" => Some(Service::UploadPack),
+            "git-receive-pack" => Some(Service::ReceivePack),
+            "git-upload-archive" => Some(Service::UploadArchive),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Service::UploadPack => "git-upload-pack",
+            Service::ReceivePack => "git-receive-pack",
+            Service::UploadArchive => "git-upload-archive",
+        }
+    }
+
+    fn capabilities(self) -> &'static [&'static str] {
+        match self {
+            Service::UploadPack => &[
+                "multi_ack",
+                "multi_ack_detailed",
+                "side-band",
+                "side-band-64k",
+                "ofs-delta",
+                "no-progress",
+                "object-format=sha1",
+                AGENT,
+            ],
+            Service::ReceivePack => &[
+                "report-status",
+                "delete-refs",
+                "ofs-delta",
+                "side-band-64k",
+                "atomic",
+                "push-options",
+                "object-format=sha1",
+                AGENT,
+            ],
+            // upload-archive doesn't advertise anything.
+            Service::UploadArchive => &[],
+        }
+    }
+}
+
+/// A ref as the client gets to see it.
+pub(crate) struct AdvertisedRef {
+    pub(super) name: BString,
+    pub(super) id: ObjectId,
+    /// The object an annotated tag ultimately points at.
+    pub(super) peeled: Option<ObjectId>,
+    /// The ref a symbolic ref like `HEAD` points at.
+    pub(super) symref_target: Option<BString>,
+}
+
+/// Collect `HEAD` (unless it's unborn) followed by every ref under `refs/`.
+pub(crate) fn list_refs(repo: &gix::Repository) -> Result<Vec<AdvertisedRef>, Error> {
+    let mut refs = vec![];
+    let head = repo.head().map_err(Error::failed_to_list_refs)?;
+    if let Some(id) = head.id() {
+        refs.push(AdvertisedRef {
+            name: "HEAD".into(),
+            id: id.detach(),
+            peeled: None,
+            symref_target: head.referent_name().map(|name| name.as_bstr().to_owned()),
+        });
+    }
+    let platform = repo.references().map_err(Error::failed_to_list_refs)?;
+    for reference in platform.all().map_err(Error::failed_to_list_refs)? {
+        let Some(mut reference) = reference.ok() else {
+            continue;
+        };
+        let Some(id) = reference.try_id().map(|id| id.detach()) else {
+            continue;
+        };
+        let peeled = reference
+            .peel_to_id_in_place()
+            .ok()
+            .map(|peeled| peeled.detach())
+            .filter(|peeled| *peeled != id);
+        refs.push(AdvertisedRef {
+            name: reference.name().as_bstr().to_owned(),
+            id,
+            peeled,
+            symref_target: None,
+        });
+    }
+    Ok(refs)
+}
+
+/// Pass on what came of listing refs, telling the client when that failed, as
+/// there's nothing it could go on with.
+pub(super) async fn tell_client<T, W>(listed: Result<T, Error>, writer: &mut W) -> Result<T, Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    if listed.is_err() {
+        gix_packetline::encode::error_to_write(b"failed to list refs", &mut *writer).await?;
+        writer.flush().await?;
+    }
+    listed
+}
+
+/// The ids a client may ask for, which are the tips of the advertised refs.
+pub(super) fn allowed_wants(refs: &[AdvertisedRef]) -> HashSet<ObjectId> {
+    refs.iter()
+        .flat_map(|r| std::iter::once(r.id).chain(r.peeled))
+        .collect()
+}
+
+/// Write the protocol v0/v1 ref advertisement for `service`, one pkt-line per ref
+/// with our capabilities after a NUL on the first one.
+///
+/// Like `git receive-pack`, pushes only get to see the refs under `refs/`,
+/// without `HEAD` or peeled tags.
+pub(crate) async fn reference_discovery<W>(
+    writer: &mut W,
+    refs: &[AdvertisedRef],
+    service: Service,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut capabilities = service.capabilities().join(" ");
+    let mut lines: Vec<(ObjectId, BString)> = vec![];
+    for r in refs {
+        if service == Service::ReceivePack && !r.name.starts_with(b"refs/") {
+            continue;
+        }
+        if let Some(target) = &r.symref_target {
+            capabilities.push_str(&format!(" symref={}:{}", r.name, target));
+        }
+        lines.push((r.id, r.name.clone()));
+        // annotated tags are followed by the object they point at.
+        if let (Service::UploadPack, Some(peeled)) = (service, r.peeled) {
+            let mut name = r.name.clone();
+            name.extend_from_slice(b"^{}");
+            lines.push((peeled, name));
+        }
+    }
+    // An empty repository still has to advertise its capabilities.
+    if lines.is_empty() {
+        lines.push((
+            ObjectId::null(gix::hash::Kind::Sha1),
+            BString::from("capabilities^{}"),
+        ));
+    }
+
+    let mut writer = gix_packetline::Writer::new(writer).text_mode();
+    for (i, (id, name)) in lines.iter().enumerate() {
+        let mut pkt_line = format!("{id} ").into_bytes();
+        pkt_line.extend_from_slice(name);
+        if i == 0 {
+            pkt_line.push(b'\0');
+            pkt_line.extend_from_slice(capabilities.as_bytes());
+        }
+        writer.write_all(&pkt_line).await?;
+    }
+    gix_packetline::encode::flush_to_write(writer.inner_mut()).await?;
+    writer.flush().await
+}
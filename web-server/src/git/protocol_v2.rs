@@ -0,0 +1,291 @@
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use gix::bstr::{BString, ByteSlice};
+use gix::ObjectId;
+use gix_packetline::{PacketLineRef, StreamingPeekableIter};
+
+use super::advertise::{allowed_wants, list_refs, tell_client, AGENT};
+use super::upload_pack::{send_pack, SideBand};
+use super::{Error, ServiceContext};
+
+const CAPABILITIES: &[&str] = &[
+    AGENT,
+    "ls-refs=unborn",
+    "fetch",
+    "server-option",
+    "object-format=sha1",
+    "object-info",
+];
+
+/// A single command request, made up of `command=<name>` and the arguments
+/// following the delimiter. Capability lines like `agent` carry nothing we act on.
+struct Request {
+    command: BString,
+    arguments: Vec<BString>,
+}
+
+/// Read the next request, or `None` once the client is done talking to us.
+async fn read_request<R>(reader: &mut StreamingPeekableIter<R>) -> Result<Option<Request>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut command = None;
+    let mut arguments = vec![];
+    reader.reset();
+    while let Some(line) = reader.read_line().await {
+        let line = match line {
+            // Clients may also just hang up in between requests.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && command.is_none() => {
+                return Ok(None)
+            }
+            line => line?.map_err(|_| Error::InvalidPacketLine)?,
+        };
+        let PacketLineRef::Data(data) = line else {
+            break;
+        };
+        let data = data.strip_suffix(b"\n").unwrap_or(data);
+        if let Some(name) = data.strip_prefix(b"command=") {
+            command = Some(BString::from(name));
+        }
+    }
+    let command = match (reader.stopped_at(), command) {
+        (Some(PacketLineRef::Delimiter | PacketLineRef::Flush), Some(command)) => command,
+        // A lone flush-pkt or EOF ends the session.
+        (Some(PacketLineRef::Flush) | None, None) => return Ok(None),
+        _ => return Err(Error::InvalidPacketLine),
+    };
+    if reader.stopped_at() == Some(PacketLineRef::Delimiter) {
+        reader.reset();
+        while let Some(line) = reader.read_line().await {
+            let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+            let PacketLineRef::Data(data) = line else {
+                break;
+            };
+            arguments.push(BString::from(data.strip_suffix(b"\n").unwrap_or(data)));
+        }
+        if reader.stopped_at() != Some(PacketLineRef::Flush) {
+            return Err(Error::InvalidPacketLine);
+        }
+    }
+    Ok(Some(Request { command, arguments }))
+}
+
+/// List the refs matching any of the requested `ref-prefix`es.
+fn ls_refs(repo: &gix::Repository, arguments: &[BString]) -> Result<Vec<BString>, Error> {
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut unborn = false;
+    let mut prefixes = vec![];
+    for argument in arguments {
+        match argument.as_bytes() {
+            b"symrefs" => symrefs = true,
+            b"peel" => peel = true,
+            b"unborn" => unborn = true,
+            argument => {
+                if let Some(prefix) = argument.strip_prefix(b"ref-prefix ") {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+    }
+    let wanted = |name: &[u8]| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+    let mut lines = vec![];
+    if unborn && wanted(b"HEAD") {
+        let head = repo.head().map_err(Error::failed_to_list_refs)?;
+        if let gix::head::Kind::Unborn(target) = head.kind {
+            let mut line = BString::from("unborn HEAD");
+            if symrefs {
+                line.extend_from_slice(b" symref-target:");
+                line.extend_from_slice(target.as_bstr());
+            }
+            lines.push(line);
+        }
+    }
+    for r in list_refs(repo)? {
+        if !wanted(&r.name) {
+            continue;
+        }
+        let mut line = BString::from(format!("{} ", r.id));
+        line.extend_from_slice(&r.name);
+        if let (true, Some(target)) = (symrefs, &r.symref_target) {
+            line.extend_from_slice(b" symref-target:");
+            line.extend_from_slice(target);
+        }
+        if let (true, Some(peeled)) = (peel, r.peeled) {
+            line.extend_from_slice(format!(" peeled:{peeled}").as_bytes());
+        }
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Report the size of each requested object. Unknown objects get an empty size.
+fn object_info(repo: &gix::Repository, arguments: &[BString]) -> Result<Vec<BString>, Error> {
+    let mut size = false;
+    let mut lines = vec![];
+    for argument in arguments {
+        if argument == "size" {
+            size = true;
+        } else if let Some(oid) = argument.strip_prefix(b"oid ") {
+            let oid = ObjectId::from_hex(oid).map_err(|_| Error::InvalidPacketLine)?;
+            let mut line = BString::from(format!("{oid} "));
+            if let (true, Ok(header)) = (size, repo.find_header(oid)) {
+                line.extend_from_slice(header.size().to_string().as_bytes());
+            }
+            lines.push(line);
+        }
+    }
+    if size {
+        lines.insert(0, "size".into());
+    }
+    Ok(lines)
+}
+
+/// Arguments of a `fetch` request we act on.
+struct FetchArgs {
+    wants: Vec<ObjectId>,
+    haves: Vec<ObjectId>,
+    done: bool,
+}
+
+impl FetchArgs {
+    fn parse(arguments: &[BString]) -> Result<Self, Error> {
+        let mut args = FetchArgs {
+            wants: vec![],
+            haves: vec![],
+            done: false,
+        };
+        let parse_id =
+            |id: &[u8]| ObjectId::from_hex(id).map_err(|_| Error::InvalidWant(id.into()));
+        for argument in arguments {
+            if let Some(want) = argument.strip_prefix(b"want ") {
+                args.wants.push(parse_id(want)?);
+            } else if let Some(have) = argument.strip_prefix(b"have ") {
+                args.haves.push(parse_id(have)?);
+            } else if argument == "done" {
+                args.done = true;
+            }
+            // the pack is always ofs-delta'd and never thin, and we don't send
+            // progress, so there's nothing to do for the remaining arguments.
+        }
+        Ok(args)
+    }
+}
+
+/// Answer a `fetch` request. Until the client is `done` we only acknowledge
+/// the objects we have in common, after which the packfile section follows.
+async fn fetch<W>(
+    writer: &mut gix_packetline::Writer<W>,
+    ctx: &ServiceContext,
+    arguments: &[BString],
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let args = FetchArgs::parse(arguments)?;
+    let refs = list_refs(&ctx.repo.to_thread_local());
+    let allowed = allowed_wants(&tell_client(refs, writer.inner_mut()).await?);
+    let (bad_want, common) = {
+        let repo = ctx.repo.to_thread_local();
+        let bad_want = args.wants.iter().find(|want| !allowed.contains(*want));
+        let common: Vec<ObjectId> = args
+            .haves
+            .into_iter()
+            .filter(|have| repo.has_object(have))
+            .collect();
+        (bad_want.copied(), common)
+    };
+    if let Some(want) = bad_want {
+        gix_packetline::encode::error_to_write(
+            format!("upload-pack: not our ref {want}").as_bytes(),
+            writer.inner_mut(),
+        )
+        .await?;
+        return Err(Error::InvalidWant(want.to_string().into()));
+    }
+
+    if !args.done {
+        writer.write_all(b"acknowledgments").await?;
+        if common.is_empty() {
+            writer.write_all(b"NAK").await?;
+        }
+        for id in &common {
+            writer.write_all(format!("ACK {id}").as_bytes()).await?;
+        }
+        gix_packetline::encode::flush_to_write(writer.inner_mut()).await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+
+    writer.write_all(b"packfile").await?;
+    send_pack(
+        writer.inner_mut(),
+        ctx.repo_path.clone(),
+        args.wants,
+        common,
+        SideBand::Large,
+    )
+    .await
+}
+
+/// Advertise the protocol v2 capabilities, which is what starts off a session.
+pub(crate) async fn advertise_capabilities<W>(writer: W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = gix_packetline::Writer::new(writer).text_mode();
+    writer.write_all(b"version 2").await?;
+    for capability in CAPABILITIES {
+        writer.write_all(capability.as_bytes()).await?;
+    }
+    gix_packetline::encode::flush_to_write(writer.inner_mut()).await?;
+    writer.flush().await
+}
+
+/// Serve protocol v2 requests until the client hangs up, or just a single one
+/// if the context is `stateless`.
+pub(crate) async fn serve<R, W>(reader: R, writer: W, ctx: &ServiceContext) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = gix_packetline::Writer::new(writer).text_mode();
+    let mut reader = StreamingPeekableIter::new(
+        reader,
+        &[PacketLineRef::Flush, PacketLineRef::Delimiter],
+        false,
+    );
+    while let Some(request) = read_request(&mut reader).await? {
+        let lines = match request.command.as_bytes() {
+            b"ls-refs" => {
+                let lines = ls_refs(&ctx.repo.to_thread_local(), &request.arguments);
+                tell_client(lines, writer.inner_mut()).await?
+            }
+            b"object-info" => object_info(&ctx.repo.to_thread_local(), &request.arguments)?,
+            b"fetch" => {
+                fetch(&mut writer, ctx, &request.arguments).await?;
+                if ctx.stateless {
+                    break;
+                }
+                continue;
+            }
+            _ => {
+                gix_packetline::encode::error_to_write(
+                    format!("unknown command {}", request.command).as_bytes(),
+                    writer.inner_mut(),
+                )
+                .await?;
+                return Err(Error::UnknownCommand);
+            }
+        };
+        for line in lines {
+            writer.write_all(&line).await?;
+        }
+        gix_packetline::encode::flush_to_write(writer.inner_mut()).await?;
+        writer.flush().await?;
+        if ctx.stateless {
+            break;
+        }
+    }
+    Ok(())
+}
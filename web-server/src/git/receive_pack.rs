@@ -0,0 +1,283 @@
+use std::sync::atomic::AtomicBool;
+
+use futures::{AsyncWrite, AsyncWriteExt};
+use gix::bstr::{BString, ByteSlice};
+use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+use gix::refs::Target;
+use gix::ObjectId;
+use gix_packetline::{PacketLineRef, StreamingPeekableIter};
+use tokio_util::compat::TokioAsyncReadCompatExt as _;
+use tokio_util::io::SyncIoBridge;
+
+use super::{Error, ServiceContext};
+
+/// A single `<old-oid> <new-oid> <ref-name>` command sent by the client.
+#[derive(Debug)]
+struct RefUpdate {
+    old: ObjectId,
+    new: ObjectId,
+    name: BString,
+}
+
+impl RefUpdate {
+    fn parse(line: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::InvalidRefUpdate(line.into());
+        let mut parts = line.splitn(3, |b| *b == b' ');
+        let (Some(old), Some(new), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            old: ObjectId::from_hex(old).map_err(|_| invalid())?,
+            new: ObjectId::from_hex(new).map_err(|_| invalid())?,
+            name: name.into(),
+        })
+    }
+
+    fn is_delete(&self) -> bool {
+        self.new.is_null()
+    }
+}
+
+/// Read the command list up to the flush-pkt. The first command carries the
+/// capabilities the client selected after a NUL byte.
+async fn read_commands<R>(
+    reader: &mut StreamingPeekableIter<R>,
+) -> Result<(Vec<RefUpdate>, Vec<BString>), Error>
+where
+    R: futures::AsyncRead + Unpin,
+{
+    let mut commands = vec![];
+    let mut capabilities = vec![];
+    while let Some(line) = reader.read_line().await {
+        let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+        let PacketLineRef::Data(data) = line else {
+            break;
+        };
+        let data = data.strip_suffix(b"\n").unwrap_or(data);
+        let data = match data.split_once_str(b"\0") {
+            Some((data, caps)) => {
+                capabilities.extend(caps.split_str(b" ").map(BString::from));
+                data
+            }
+            None => data,
+        };
+        // we don't advertise `shallow`, but a shallow client may still tell us about its boundary.
+        if data.starts_with(b"shallow ") {
+            continue;
+        }
+        commands.push(RefUpdate::parse(data)?);
+    }
+    Ok((commands, capabilities))
+}
+
+/// Index the incoming packfile straight into the object database of `repo`.
+fn ingest_pack(
+    repo: &gix::Repository,
+    pack: &mut dyn std::io::BufRead,
+) -> Result<(), gix_pack::bundle::write::Error> {
+    let should_interrupt = AtomicBool::new(false);
+    gix_pack::Bundle::write_to_directory(
+        pack,
+        Some(&repo.objects.store_ref().path().join("pack")),
+        &mut gix::progress::Discard,
+        &should_interrupt,
+        Some(repo.objects.clone()),
+        gix_pack::bundle::write::Options {
+            object_hash: repo.object_hash(),
+            ..Default::default()
+        },
+    )?;
+    Ok(())
+}
+
+/// Turn a single command into a ref edit, returning the reason to report back
+/// to the client if it can't be applied.
+fn prepare_edit(repo: &gix::Repository, cmd: &RefUpdate) -> Result<RefEdit, &'static str> {
+    if !cmd.name.starts_with(b"refs/") {
+        return Err("funny refname");
+    }
+    let name: gix::refs::FullName = cmd.name.clone().try_into().map_err(|_| "funny refname")?;
+    let expected = if cmd.old.is_null() && cmd.is_delete() {
+        // like git, deleting a ref the client never saw doesn't check anything.
+        PreviousValue::Any
+    } else if cmd.old.is_null() {
+        PreviousValue::MustNotExist
+    } else {
+        PreviousValue::MustExistAndMatch(Target::Object(cmd.old))
+    };
+    let change = if cmd.is_delete() {
+        Change::Delete {
+            expected,
+            log: RefLog::AndReference,
+        }
+    } else {
+        if !repo.has_object(cmd.new) {
+            return Err("missing necessary objects");
+        }
+        Change::Update {
+            log: LogChange {
+                mode: RefLog::AndReference,
+                force_create_reflog: false,
+                message: "push".into(),
+            },
+            expected,
+            new: Target::Object(cmd.new),
+        }
+    };
+    Ok(RefEdit {
+        change,
+        name,
+        deref: false,
+    })
+}
+
+/// Apply `commands`, either one by one or, for atomic pushes, all or nothing.
+fn update_refs(
+    repo: &gix::Repository,
+    commands: &[RefUpdate],
+    atomic: bool,
+) -> Vec<Result<(), &'static str>> {
+    let edits: Vec<_> = commands.iter().map(|cmd| prepare_edit(repo, cmd)).collect();
+    if !atomic {
+        return edits
+            .into_iter()
+            .zip(commands)
+            .map(|(edit, cmd)| {
+                repo.edit_reference(edit?).map_err(|e| {
+                    eprintln!("failed to update {}: {e}", cmd.name);
+                    "failed to update ref"
+                })?;
+                Ok(())
+            })
+            .collect();
+    }
+
+    if edits.iter().any(Result::is_err) {
+        return edits
+            .into_iter()
+            .map(|edit| edit.and(Err("atomic push failure")))
+            .collect();
+    }
+    match repo.edit_references(edits.into_iter().map(Result::unwrap)) {
+        Ok(_) => commands.iter().map(|_| Ok(())).collect(),
+        Err(e) => {
+            eprintln!("failed to apply atomic push: {e}");
+            commands
+                .iter()
+                .map(|_| Err("atomic transaction failed"))
+                .collect()
+        }
+    }
+}
+
+/// Send the `report-status` lines, multiplexed over band 1 if the client asked
+/// for a side-band.
+async fn send_report<W>(writer: &mut W, report: Vec<BString>, side_band: bool) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut encoded = vec![];
+    {
+        let mut encoded = gix_packetline::Writer::new(&mut encoded).text_mode();
+        for line in report {
+            encoded.write_all(&line).await?;
+        }
+        gix_packetline::encode::flush_to_write(encoded.inner_mut()).await?;
+    }
+    if side_band {
+        for data in encoded.chunks(65520 - 4 - 1) {
+            gix_packetline::encode::band_to_write(
+                gix_packetline::Channel::Data,
+                data,
+                &mut *writer,
+            )
+            .await?;
+        }
+        gix_packetline::encode::flush_to_write(&mut *writer).await?;
+    } else {
+        writer.write_all(&encoded).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Process a push once the refs were advertised: read the commands and the
+/// packfile following them, update the refs and report back.
+pub(crate) async fn receive_pack<R, W>(
+    reader: R,
+    mut writer: W,
+    ctx: &ServiceContext,
+) -> Result<(), Error>
+where
+    R: tokio::io::AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin,
+{
+    let mut repo = ctx.repo.to_thread_local();
+    let mut reader = StreamingPeekableIter::new(reader.compat(), &[PacketLineRef::Flush], false);
+    let (commands, capabilities) = read_commands(&mut reader).await?;
+
+    // Client early exited without doing anything.
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    let has = |cap: &str| capabilities.iter().any(|c| c == cap);
+    // nothing acts on push options yet, but they're in the way of the pack.
+    if has("push-options") {
+        reader.reset();
+        while let Some(line) = reader.read_line().await {
+            let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+            if !matches!(line, PacketLineRef::Data(_)) {
+                break;
+            }
+        }
+    }
+
+    // A packfile follows the commands unless every command is a delete.
+    let reader = reader.into_inner().into_inner();
+    let unpack_result = if commands.iter().all(RefUpdate::is_delete) {
+        Ok(())
+    } else {
+        let repo = &repo;
+        tokio::task::block_in_place(move || {
+            let mut pack = std::io::BufReader::new(SyncIoBridge::new(reader));
+            ingest_pack(repo, &mut pack)
+        })
+    };
+
+    let mut report = vec![];
+    match &unpack_result {
+        Ok(()) => {
+            report.push(BString::from("unpack ok"));
+            // the reflog records who pushed, if we know.
+            if let Some(identity) = &ctx.identity {
+                let mut config = repo.config_snapshot_mut();
+                config
+                    .set_value(&gix::config::tree::Committer::NAME, identity.as_str())
+                    .and_then(|_| config.set_value(&gix::config::tree::Committer::EMAIL, ""))
+                    .map_err(|_| Error::InvalidCommitter)?;
+            }
+            repo.committer_or_set_generic_fallback()
+                .map_err(|_| Error::InvalidCommitter)?;
+            let results = update_refs(&repo, &commands, has("atomic"));
+            for (cmd, result) in commands.iter().zip(results) {
+                report.push(match result {
+                    Ok(()) => format!("ok {}", cmd.name).into(),
+                    Err(reason) => format!("ng {} {reason}", cmd.name).into(),
+                });
+            }
+        }
+        Err(e) => {
+            eprintln!("failed to unpack pushed objects: {e}");
+            report.push(BString::from("unpack index-pack failed"));
+            for cmd in &commands {
+                report.push(format!("ng {} unpacker error", cmd.name).into());
+            }
+        }
+    }
+
+    if has("report-status") {
+        send_report(&mut writer, report, has("side-band-64k")).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,525 @@
+//! The services driven in-process over a `tokio::io::duplex`, the way any
+//! transport does: advertise, then serve whatever the client has to say.
+
+use std::io::Write as _;
+use std::path::Path;
+
+use git2::Oid;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use super::*;
+
+#[derive(Debug, PartialEq)]
+enum Pkt {
+    Flush,
+    Delimiter,
+    Data(Vec<u8>),
+}
+
+fn pkt(line: &str) -> Vec<u8> {
+    format!("{:04x}{line}", line.len() + 4).into_bytes()
+}
+
+const FLUSH: &[u8] = b"0000";
+const DELIMITER: &[u8] = b"0001";
+
+fn parse_pkts(mut bytes: &[u8]) -> Vec<Pkt> {
+    let mut pkts = vec![];
+    while !bytes.is_empty() {
+        let len = std::str::from_utf8(&bytes[..4]).unwrap();
+        let len = usize::from_str_radix(len, 16).unwrap();
+        pkts.push(match len {
+            0 => Pkt::Flush,
+            1 => Pkt::Delimiter,
+            len => Pkt::Data(bytes[4..len].to_vec()),
+        });
+        bytes = &bytes[len.max(4)..];
+    }
+    pkts
+}
+
+/// The text lines of `pkts`, leaving out side-band data, with flush- and
+/// delim-pkts as they're written.
+fn text(pkts: &[Pkt]) -> Vec<String> {
+    pkts.iter()
+        .filter_map(|pkt| match pkt {
+            Pkt::Flush => Some("0000".to_owned()),
+            Pkt::Delimiter => Some("0001".to_owned()),
+            Pkt::Data(data) if matches!(data.first(), Some(1..=3)) => None,
+            Pkt::Data(data) => {
+                let data = data.strip_suffix(b"\n").unwrap_or(data);
+                Some(String::from_utf8_lossy(data).into_owned())
+            }
+        })
+        .collect()
+}
+
+/// What came over side-band `band`.
+fn band(pkts: &[Pkt], band: u8) -> Vec<u8> {
+    pkts.iter()
+        .filter_map(|pkt| match pkt {
+            Pkt::Data(data) if data.first() == Some(&band) => Some(&data[1..]),
+            _ => None,
+        })
+        .flatten()
+        .copied()
+        .collect()
+}
+
+/// What follows the advertisement, which ends at the first flush-pkt.
+fn after_advertisement(pkts: &[Pkt]) -> &[Pkt] {
+    let end = pkts.iter().position(|pkt| *pkt == Pkt::Flush).unwrap();
+    &pkts[end + 1..]
+}
+
+/// Serve `service` to a client that says `input` and hangs up, returning how
+/// serving went and all the client got back, the advertisement included.
+async fn exchange(
+    ctx: &ServiceContext,
+    service: Service,
+    input: &[u8],
+) -> (Result<(), Error>, Vec<Pkt>) {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let (mut from_server, mut to_server) = tokio::io::split(client);
+    let (reader, mut writer) = tokio::io::split(server);
+    let served = async move {
+        advertise(ctx, service, &mut writer).await?;
+        serve(ctx, service, reader, writer).await
+    };
+    let talked = async move {
+        to_server.write_all(input).await.unwrap();
+        to_server.shutdown().await.unwrap();
+    };
+    let mut output = vec![];
+    let (served, (), read) = tokio::join!(served, talked, from_server.read_to_end(&mut output));
+    read.unwrap();
+    (served, parse_pkts(&output))
+}
+
+/// The history every repository here starts with: `main` at `second`, whose
+/// parent `first` is tagged `v1` with the annotated tag `tag`.
+struct History {
+    first: Oid,
+    second: Oid,
+    tag: Oid,
+}
+
+/// Commit `content` as the README on top of `parents`. The same commits come
+/// out with the same ids in every repository.
+fn commit(repo: &git2::Repository, parents: &[Oid], content: &str) -> Oid {
+    let signature = git2::Signature::new(
+        "Alice",
+        "alice@example.com",
+        &git2::Time::new(1_700_000_000, 0),
+    )
+    .unwrap();
+    let blob = repo.blob(content.as_bytes()).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    tree.insert("README", blob, 0o100644).unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let parents: Vec<_> = parents
+        .iter()
+        .map(|parent| repo.find_commit(*parent).unwrap())
+        .collect();
+    let parents: Vec<_> = parents.iter().collect();
+    repo.commit(None, &signature, &signature, content, &tree, &parents)
+        .unwrap()
+}
+
+fn history(repo: &git2::Repository) -> History {
+    let first = commit(repo, &[], "first");
+    let second = commit(repo, &[first], "second");
+    let signature = git2::Signature::new(
+        "Alice",
+        "alice@example.com",
+        &git2::Time::new(1_700_000_000, 0),
+    )
+    .unwrap();
+    let tag = repo
+        .tag(
+            "v1",
+            &repo.find_object(first, None).unwrap(),
+            &signature,
+            "v1",
+            false,
+        )
+        .unwrap();
+    repo.reference("refs/heads/main", second, false, "")
+        .unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    History { first, second, tag }
+}
+
+fn bare_repo() -> (TempDir, git2::Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    (dir, repo)
+}
+
+fn context(path: &Path, protocol: &[&str]) -> ServiceContext {
+    ServiceContext {
+        repo_path: path.to_owned(),
+        repo: gix::open(path).unwrap().into_sync(),
+        identity: Some("alice".to_owned()),
+        protocol: protocol.iter().map(|param| param.to_string()).collect(),
+        stateless: false,
+        archive_formats: vec![],
+    }
+}
+
+/// A pack of what's in `repo` from `tip` on but not from `hide` on.
+fn pack(repo: &git2::Repository, tip: Oid, hide: Option<Oid>) -> Vec<u8> {
+    let mut walk = repo.revwalk().unwrap();
+    walk.push(tip).unwrap();
+    if let Some(hide) = hide {
+        walk.hide(hide).unwrap();
+    }
+    let mut builder = repo.packbuilder().unwrap();
+    builder.insert_walk(&mut walk).unwrap();
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf).unwrap();
+    buf.to_vec()
+}
+
+/// Index `pack` into an empty repository.
+fn unpack(pack: &[u8]) -> (TempDir, git2::Repository) {
+    let (dir, repo) = bare_repo();
+    {
+        let odb = repo.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        writer.write_all(pack).unwrap();
+        writer.commit().unwrap();
+    }
+    (dir, repo)
+}
+
+fn target(repo: &git2::Repository, name: &str) -> Option<Oid> {
+    repo.find_reference(name).ok().and_then(|r| r.target())
+}
+
+/// A pushed commit on top of `second`, with the pack that carries it.
+fn pushed_commit() -> (Oid, Vec<u8>) {
+    let (_dir, client) = bare_repo();
+    let History { second, .. } = history(&client);
+    let third = commit(&client, &[second], "third");
+    (third, pack(&client, third, Some(second)))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn push_updates_refs_and_reports_status() {
+    let (dir, repo) = bare_repo();
+    let History { second, .. } = history(&repo);
+    let (third, pack) = pushed_commit();
+
+    let null = Oid::zero();
+    let mut input = pkt(&format!(
+        "{second} {third} refs/heads/main\0report-status agent=git/2\n"
+    ));
+    input.extend(pkt(&format!("{null} {third} refs/heads/topic\n")));
+    input.extend(FLUSH);
+    input.extend(pack);
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::ReceivePack, &input).await;
+    served.unwrap();
+
+    let advertisement = text(&pkts);
+    assert!(advertisement[0].starts_with(&format!("{second} refs/heads/main\0report-status ")));
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [
+            "unpack ok",
+            "ok refs/heads/main",
+            "ok refs/heads/topic",
+            "0000"
+        ]
+    );
+    assert_eq!(target(&repo, "refs/heads/main"), Some(third));
+    assert_eq!(target(&repo, "refs/heads/topic"), Some(third));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn push_reports_over_side_band() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, .. } = history(&repo);
+    let (third, pack) = pushed_commit();
+
+    // the second update is stale, which only turns down that one.
+    let mut input = pkt(&format!(
+        "{second} {third} refs/heads/main\0report-status side-band-64k\n"
+    ));
+    input.extend(pkt(&format!("{first} {third} refs/heads/old\n")));
+    input.extend(FLUSH);
+    input.extend(pack);
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::ReceivePack, &input).await;
+    served.unwrap();
+
+    let pkts = after_advertisement(&pkts);
+    assert_eq!(text(pkts), ["0000"]);
+    assert_eq!(
+        text(&parse_pkts(&band(pkts, 1))),
+        [
+            "unpack ok",
+            "ok refs/heads/main",
+            "ng refs/heads/old failed to update ref",
+            "0000"
+        ]
+    );
+    assert_eq!(target(&repo, "refs/heads/main"), Some(third));
+    assert_eq!(target(&repo, "refs/heads/old"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn atomic_push_is_all_or_nothing() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, .. } = history(&repo);
+    let (third, pack) = pushed_commit();
+    let ctx = context(dir.path(), &[]);
+
+    // one of the updates can't even be made.
+    let mut input = pkt(&format!(
+        "{second} {third} refs/heads/main\0report-status atomic\n"
+    ));
+    input.extend(pkt(&format!("{second} {third} HEAD\n")));
+    input.extend(FLUSH);
+    input.extend(&pack);
+    let (served, pkts) = exchange(&ctx, Service::ReceivePack, &input).await;
+    served.unwrap();
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [
+            "unpack ok",
+            "ng refs/heads/main atomic push failure",
+            "ng HEAD funny refname",
+            "0000"
+        ]
+    );
+    assert_eq!(target(&repo, "refs/heads/main"), Some(second));
+
+    // one of them is stale, which only shows once they're applied.
+    let mut input = pkt(&format!(
+        "{second} {third} refs/heads/main\0report-status atomic\n"
+    ));
+    input.extend(pkt(&format!("{first} {third} refs/heads/v2\n")));
+    input.extend(FLUSH);
+    input.extend(&pack);
+    let (served, pkts) = exchange(&ctx, Service::ReceivePack, &input).await;
+    served.unwrap();
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [
+            "unpack ok",
+            "ng refs/heads/main atomic transaction failed",
+            "ng refs/heads/v2 atomic transaction failed",
+            "0000"
+        ]
+    );
+    assert_eq!(target(&repo, "refs/heads/main"), Some(second));
+    assert_eq!(target(&repo, "refs/heads/v2"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_only_push_comes_without_a_pack() {
+    let (dir, repo) = bare_repo();
+    let History { tag, .. } = history(&repo);
+    let null = Oid::zero();
+
+    let mut input = pkt(&format!(
+        "{tag} {null} refs/tags/v1\0report-status atomic\n"
+    ));
+    // deleting what doesn't exist is fine, as long as the client didn't expect it to.
+    input.extend(pkt(&format!("{null} {null} refs/heads/gone\n")));
+    input.extend(FLUSH);
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::ReceivePack, &input).await;
+    served.unwrap();
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        ["unpack ok", "ok refs/tags/v1", "ok refs/heads/gone", "0000"]
+    );
+    assert_eq!(target(&repo, "refs/tags/v1"), None);
+}
+
+#[tokio::test]
+async fn fetch_acknowledges_common_commits() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, .. } = history(&repo);
+    let unknown = Oid::from_bytes(&[1; 20]).unwrap();
+
+    let mut input = pkt(&format!("want {second} multi_ack_detailed side-band-64k\n"));
+    input.extend(FLUSH);
+    input.extend(pkt(&format!("have {unknown}\n")));
+    input.extend(pkt(&format!("have {first}\n")));
+    input.extend(FLUSH);
+    input.extend(pkt("done\n"));
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::UploadPack, &input).await;
+    served.unwrap();
+
+    let pkts = after_advertisement(&pkts);
+    assert_eq!(
+        text(pkts),
+        [
+            format!("ACK {first} common"),
+            "NAK".to_owned(),
+            format!("ACK {first}"),
+            "0000".to_owned()
+        ]
+    );
+    // what's in common stays out of the pack.
+    let (_dir, fetched) = unpack(&band(pkts, 1));
+    assert!(fetched.find_commit(second).is_ok());
+    assert!(fetched.find_commit(first).is_err());
+}
+
+#[tokio::test]
+async fn fetch_without_common_commits_gets_a_nak() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, tag } = history(&repo);
+    let unknown = Oid::from_bytes(&[1; 20]).unwrap();
+
+    let mut input = pkt(&format!("want {second} multi_ack side-band\n"));
+    input.extend(pkt(&format!("want {tag}\n")));
+    input.extend(FLUSH);
+    input.extend(pkt(&format!("have {unknown}\n")));
+    input.extend(pkt("done\n"));
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::UploadPack, &input).await;
+    served.unwrap();
+
+    let pkts = after_advertisement(&pkts);
+    assert_eq!(text(pkts), ["NAK", "0000"]);
+    let (_dir, fetched) = unpack(&band(pkts, 1));
+    assert!(fetched.find_commit(first).is_ok());
+    assert!(fetched.find_commit(second).is_ok());
+    assert!(fetched.find_tag(tag).is_ok());
+}
+
+#[tokio::test]
+async fn fetch_without_multi_ack_gets_a_single_ack() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, .. } = history(&repo);
+
+    let mut input = pkt(&format!("want {second} side-band-64k\n"));
+    input.extend(FLUSH);
+    input.extend(pkt(&format!("have {first}\n")));
+    input.extend(FLUSH);
+    input.extend(pkt(&format!("have {second}\n")));
+    input.extend(FLUSH);
+    input.extend(pkt("done\n"));
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::UploadPack, &input).await;
+    served.unwrap();
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [format!("ACK {first}"), "0000".to_owned()]
+    );
+}
+
+#[tokio::test]
+async fn fetch_of_what_isnt_advertised_is_turned_down() {
+    let (dir, repo) = bare_repo();
+    history(&repo);
+    let dangling = commit(&repo, &[], "dangling");
+
+    let mut input = pkt(&format!("want {dangling} side-band-64k\n"));
+    input.extend(FLUSH);
+    input.extend(pkt("done\n"));
+    let (served, pkts) = exchange(&context(dir.path(), &[]), Service::UploadPack, &input).await;
+    assert!(matches!(served, Err(Error::InvalidWant(_))));
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [format!(
+            "ERR upload-pack: not our ref want {dangling} side-band-64k"
+        )]
+    );
+}
+
+#[tokio::test]
+async fn protocol_v2_lists_refs() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, tag } = history(&repo);
+
+    let mut input = pkt("command=ls-refs\n");
+    input.extend(pkt("agent=git/2\n"));
+    input.extend(DELIMITER);
+    input.extend(pkt("symrefs\n"));
+    input.extend(pkt("peel\n"));
+    input.extend(FLUSH);
+    input.extend(pkt("command=ls-refs\n"));
+    input.extend(DELIMITER);
+    input.extend(pkt("ref-prefix refs/tags/\n"));
+    input.extend(FLUSH);
+    let ctx = context(dir.path(), &["version=2"]);
+    let (served, pkts) = exchange(&ctx, Service::UploadPack, &input).await;
+    served.unwrap();
+
+    let lines = text(&pkts);
+    assert_eq!(lines[0], "version 2");
+    assert!(lines.iter().any(|line| line == "ls-refs=unborn"));
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        [
+            format!("{second} HEAD symref-target:refs/heads/main"),
+            format!("{second} refs/heads/main"),
+            format!("{tag} refs/tags/v1 peeled:{first}"),
+            "0000".to_owned(),
+            format!("{tag} refs/tags/v1"),
+            "0000".to_owned(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn protocol_v2_lists_unborn_head() {
+    let (dir, _repo) = bare_repo();
+
+    let mut input = pkt("command=ls-refs\n");
+    input.extend(DELIMITER);
+    input.extend(pkt("symrefs\n"));
+    input.extend(pkt("unborn\n"));
+    input.extend(FLUSH);
+    let ctx = context(dir.path(), &["version=2"]);
+    let (served, pkts) = exchange(&ctx, Service::UploadPack, &input).await;
+    served.unwrap();
+    assert_eq!(
+        text(after_advertisement(&pkts)),
+        ["unborn HEAD symref-target:refs/heads/main", "0000"]
+    );
+}
+
+#[tokio::test]
+async fn protocol_v2_fetches() {
+    let (dir, repo) = bare_repo();
+    let History { first, second, .. } = history(&repo);
+    let unknown = Oid::from_bytes(&[1; 20]).unwrap();
+
+    let fetch = |done: bool| {
+        let mut input = pkt("command=fetch\n");
+        input.extend(DELIMITER);
+        input.extend(pkt(&format!("want {second}\n")));
+        input.extend(pkt(&format!("have {unknown}\n")));
+        input.extend(pkt(&format!("have {first}\n")));
+        if done {
+            input.extend(pkt("done\n"));
+        }
+        input.extend(FLUSH);
+        input
+    };
+    let mut input = fetch(false);
+    input.extend(fetch(true));
+    let ctx = context(dir.path(), &["version=2"]);
+    let (served, pkts) = exchange(&ctx, Service::UploadPack, &input).await;
+    served.unwrap();
+
+    let pkts = after_advertisement(&pkts);
+    assert_eq!(
+        text(pkts),
+        [
+            "acknowledgments".to_owned(),
+            format!("ACK {first}"),
+            "0000".to_owned(),
+            "packfile".to_owned(),
+            "0000".to_owned()
+        ]
+    );
+    let (_dir, fetched) = unpack(&band(pkts, 1));
+    assert!(fetched.find_commit(second).is_ok());
+    assert!(fetched.find_commit(first).is_err());
+}
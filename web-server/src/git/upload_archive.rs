@@ -0,0 +1,225 @@
+//! upload-archive, which makes the archives `git archive --remote` asks for.
+//!
+//! The client starts off by sending its command line as `argument` lines. Once
+//! we've made sense of them and started on the archive it gets an `ACK`, and
+//! then the archive over band 1.
+
+use std::io::{self, Write as _};
+use std::path::Path;
+
+use futures::{AsyncWrite, AsyncWriteExt};
+use gix::bstr::{BString, ByteSlice};
+use gix_packetline::{PacketLineRef, StreamingPeekableIter};
+use tokio::sync::mpsc;
+
+use super::upload_pack::SideBand;
+use super::{Error, ServiceContext};
+use crate::repositories::{self, Archive, ArchiveFormat};
+
+/// As many arguments as git takes, which is plenty for a few options and paths.
+const MAX_ARGS: usize = 64;
+
+/// What the client asked for.
+#[derive(Debug)]
+struct Request {
+    format: ArchiveFormat,
+    prefix: String,
+    tree_ish: String,
+    paths: Vec<String>,
+}
+
+impl Request {
+    /// Make sense of the `argument` lines, which are what was passed to
+    /// `git archive` but for the options the client takes care of itself. Only
+    /// the `allowed` formats may be asked for, tar being the default.
+    fn parse(lines: &[BString], allowed: &[ArchiveFormat]) -> Result<Self, String> {
+        if lines.len() > MAX_ARGS {
+            return Err("too many options".to_owned());
+        }
+        let mut arguments = vec![];
+        for line in lines {
+            let argument = line
+                .strip_prefix(b"argument ")
+                .ok_or("'argument' token or flush expected")?;
+            arguments.push(argument.to_str_lossy().into_owned());
+        }
+
+        let mut format = "tar".to_owned();
+        let mut prefix = String::new();
+        let mut positional = vec![];
+        let mut arguments = arguments.into_iter();
+        while let Some(argument) = arguments.next() {
+            let missing = |option: &str| format!("option '{option}' requires a value");
+            if argument == "--" {
+                positional.extend(arguments.by_ref());
+            } else if argument == "--format" {
+                format = arguments.next().ok_or_else(|| missing("format"))?;
+            } else if let Some(value) = argument.strip_prefix("--format=") {
+                format = value.to_owned();
+            } else if argument == "--prefix" {
+                prefix = arguments.next().ok_or_else(|| missing("prefix"))?;
+            } else if let Some(value) = argument.strip_prefix("--prefix=") {
+                prefix = value.to_owned();
+            } else if argument == "-v" || argument == "--verbose" {
+                // there's no progress to report.
+            } else if argument.len() == 2
+                && argument.starts_with('-')
+                && argument.as_bytes()[1].is_ascii_digit()
+            {
+                // compression levels, we stick to the default one.
+            } else if argument.starts_with('-') {
+                return Err(format!("unknown option '{argument}'"));
+            } else {
+                positional.push(argument);
+            }
+        }
+
+        let format: ArchiveFormat = format.parse()?;
+        if !allowed.contains(&format) {
+            return Err(format!(
+                "archive format '{}' is not allowed",
+                format.extension()
+            ));
+        }
+        let mut positional = positional.into_iter();
+        let tree_ish = positional.next().ok_or("no tree-ish given")?;
+        Ok(Request {
+            format,
+            prefix,
+            tree_ish,
+            paths: positional.collect(),
+        })
+    }
+}
+
+/// Read the `argument` lines up to the flush-pkt.
+async fn read_arguments<R>(reader: &mut StreamingPeekableIter<R>) -> Result<Vec<BString>, Error>
+where
+    R: futures::AsyncRead + Unpin,
+{
+    let mut lines = vec![];
+    while let Some(line) = reader.read_line().await {
+        let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+        let PacketLineRef::Data(data) = line else {
+            break;
+        };
+        lines.push(data.strip_suffix(b"\n").unwrap_or(data).into());
+    }
+    Ok(lines)
+}
+
+/// The tree `tree_ish` names, and the commit it's from if there is one.
+///
+/// Like git with `uploadArchive.allowUnreachable` off, only what refs lead to
+/// can be archived, as `<ref>` or `<ref>:<path>`. Objects that aren't
+/// reachable anymore stay out of reach.
+fn resolve<'r>(
+    repo: &'r git2::Repository,
+    tree_ish: &str,
+) -> io::Result<(git2::Tree<'r>, Option<git2::Commit<'r>>)> {
+    let (name, path) = match tree_ish.split_once(':') {
+        Some((name, path)) => (name, path.trim_matches('/')),
+        None => (tree_ish, ""),
+    };
+    let object = repo
+        .resolve_reference_from_short_name(name)
+        .and_then(|reference| reference.peel(git2::ObjectType::Any))
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("no such ref: {name}")))?;
+    let not_a_tree = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not a tree object: {tree_ish}"),
+        )
+    };
+    let tree = object.peel_to_tree().map_err(|_| not_a_tree())?;
+    let tree = match path {
+        "" => tree,
+        path => tree
+            .get_path(Path::new(path))
+            .and_then(|entry| entry.to_object(repo))
+            .and_then(|object| object.peel_to_tree())
+            .map_err(|_| not_a_tree())?,
+    };
+    Ok((tree, object.peel_to_commit().ok()))
+}
+
+/// Make the archive `request` asks for, sending it over `tx` as it gets
+/// written.
+fn build(repo_path: &Path, request: Request, tx: mpsc::Sender<Vec<u8>>) -> io::Result<()> {
+    let repo = git2::Repository::open_bare(repo_path).map_err(io::Error::other)?;
+    let (tree, commit) = resolve(&repo, &request.tree_ish)?;
+    let archive = Archive::new(&repo, &tree, commit, request.prefix, &request.paths)?;
+    let mut out = repositories::chunks(tx);
+    archive.write(request.format, &mut out)?;
+    out.flush()
+}
+
+/// Turn the request down, which the client shows as `NACK <message>`.
+async fn nack<W>(writer: &mut W, message: &str) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    gix_packetline::encode::text_to_write(format!("NACK {message}").as_bytes(), &mut *writer)
+        .await?;
+    gix_packetline::encode::flush_to_write(&mut *writer).await?;
+    writer.flush().await
+}
+
+/// Serve `git archive --remote`.
+pub(crate) async fn upload_archive<R, W>(
+    reader: R,
+    mut writer: W,
+    ctx: &ServiceContext,
+) -> Result<(), Error>
+where
+    R: futures::AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = StreamingPeekableIter::new(reader, &[PacketLineRef::Flush], false);
+    let lines = read_arguments(&mut reader).await?;
+    let request = match Request::parse(&lines, &ctx.archive_formats) {
+        Ok(request) => request,
+        Err(message) => {
+            nack(&mut writer, &message).await?;
+            return Err(Error::InvalidArchiveRequest(message));
+        }
+    };
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let repo_path = ctx.repo_path.clone();
+    let archiver = tokio::task::spawn_blocking(move || build(&repo_path, request, tx));
+    // archives that can't be made, like of refs that don't exist, fail before
+    // anything's been written, so the client can still be told instead of
+    // getting the ACK.
+    let Some(first) = rx.recv().await else {
+        let e = match archiver.await.expect("archiver panicked") {
+            Err(e) => e,
+            Ok(()) => io::Error::other("the archive came out empty"),
+        };
+        nack(&mut writer, &e.to_string()).await?;
+        return Err(Error::FailedToArchive(e));
+    };
+    gix_packetline::encode::text_to_write(b"ACK", &mut writer).await?;
+    gix_packetline::encode::flush_to_write(&mut writer).await?;
+
+    let mut next = Some(first);
+    while let Some(chunk) = next {
+        for data in chunk.chunks(SideBand::Large.max_payload()) {
+            gix_packetline::encode::band_to_write(gix_packetline::Channel::Data, data, &mut writer)
+                .await?;
+        }
+        next = rx.recv().await;
+    }
+    let result = archiver.await.expect("archiver panicked");
+    if let Err(e) = &result {
+        gix_packetline::encode::band_to_write(
+            gix_packetline::Channel::Error,
+            format!("failed to archive: {e}").as_bytes(),
+            &mut writer,
+        )
+        .await?;
+    }
+    gix_packetline::encode::flush_to_write(&mut writer).await?;
+    writer.flush().await?;
+    result.map_err(Error::FailedToArchive)
+}
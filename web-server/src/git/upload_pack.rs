@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use futures::{AsyncWrite, AsyncWriteExt};
+use gix::bstr::{BString, ByteSlice};
+use gix::ObjectId;
+use gix_packetline::{PacketLineRef, StreamingPeekableIter};
+use tokio::sync::mpsc;
+
+use super::advertise::{allowed_wants, list_refs, tell_client};
+use super::{Error, ServiceContext};
+
+/// How the pack gets sent back to the client, as picked through capabilities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum SideBand {
+    None,
+    Small,
+    Large,
+}
+
+impl SideBand {
+    /// The largest amount of payload that fits into a single band packet.
+    pub(super) fn max_payload(self) -> usize {
+        match self {
+            SideBand::None => 65516,
+            SideBand::Small => 999 - 4 - 1,
+            SideBand::Large => 65520 - 4 - 1,
+        }
+    }
+}
+
+/// How the client wants to be acknowledged during negotiation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MultiAck {
+    None,
+    Basic,
+    Detailed,
+}
+
+#[derive(Debug)]
+struct Wants {
+    wants: Vec<ObjectId>,
+    side_band: SideBand,
+    multi_ack: MultiAck,
+}
+
+/// Read the `want` lines up to the flush-pkt. The first one carries the
+/// capabilities the client selected.
+async fn read_wants<R>(
+    reader: &mut StreamingPeekableIter<R>,
+    advertised: &HashSet<ObjectId>,
+) -> Result<Wants, Error>
+where
+    R: futures::AsyncRead + Unpin,
+{
+    let mut wants = vec![];
+    let mut capabilities = vec![];
+    while let Some(line) = reader.read_line().await {
+        let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+        let PacketLineRef::Data(data) = line else {
+            break;
+        };
+        let data = data.strip_suffix(b"\n").unwrap_or(data);
+        let Some(want) = data.strip_prefix(b"want ") else {
+            // we don't advertise shallow or filter support, so there's nothing else
+            // we could act on.
+            continue;
+        };
+        let (id, caps) = want.split_at(want.find_byte(b' ').unwrap_or(want.len()));
+        capabilities.extend(caps.split_str(b" ").map(BString::from));
+        let id = ObjectId::from_hex(id).map_err(|_| Error::InvalidWant(data.into()))?;
+        if !advertised.contains(&id) {
+            return Err(Error::InvalidWant(data.into()));
+        }
+        wants.push(id);
+    }
+    let has = |cap: &str| capabilities.iter().any(|c| c == cap);
+    Ok(Wants {
+        wants,
+        side_band: if has("side-band-64k") {
+            SideBand::Large
+        } else if has("side-band") {
+            SideBand::Small
+        } else {
+            SideBand::None
+        },
+        multi_ack: if has("multi_ack_detailed") {
+            MultiAck::Detailed
+        } else if has("multi_ack") {
+            MultiAck::Basic
+        } else {
+            MultiAck::None
+        },
+    })
+}
+
+/// Process the client's `have` lines until it sends `done`, acknowledging the
+/// objects we have in common. Returns the common objects, or `None` if the
+/// client hung up before it was done. A `stateless` client only gets a single
+/// round per request and reconnects for the next one.
+async fn negotiate<R, W>(
+    reader: &mut StreamingPeekableIter<R>,
+    writer: &mut gix_packetline::Writer<W>,
+    repo: &gix::ThreadSafeRepository,
+    multi_ack: MultiAck,
+    stateless: bool,
+) -> Result<Option<Vec<ObjectId>>, Error>
+where
+    R: futures::AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut common = vec![];
+    loop {
+        reader.reset();
+        let mut haves = vec![];
+        let mut done = false;
+        while let Some(line) = reader.read_line().await {
+            let line = line?.map_err(|_| Error::InvalidPacketLine)?;
+            let PacketLineRef::Data(data) = line else {
+                break;
+            };
+            let data = data.strip_suffix(b"\n").unwrap_or(data);
+            if data == b"done" {
+                done = true;
+                break;
+            }
+            if let Some(have) = data.strip_prefix(b"have ") {
+                haves.push(ObjectId::from_hex(have).map_err(|_| Error::InvalidPacketLine)?);
+            }
+        }
+        if !done && reader.stopped_at().is_none() {
+            return Ok(None);
+        }
+
+        let mut responses = vec![];
+        {
+            let repo = repo.to_thread_local();
+            for have in haves {
+                if !repo.has_object(have) {
+                    continue;
+                }
+                match multi_ack {
+                    MultiAck::Detailed => responses.push(format!("ACK {have} common")),
+                    MultiAck::Basic => responses.push(format!("ACK {have} continue")),
+                    MultiAck::None if common.is_empty() => responses.push(format!("ACK {have}")),
+                    MultiAck::None => {}
+                }
+                common.push(have);
+            }
+        }
+        if done {
+            match (common.last(), multi_ack) {
+                (Some(last), MultiAck::Basic | MultiAck::Detailed) => {
+                    responses.push(format!("ACK {last}"))
+                }
+                (Some(_), MultiAck::None) => {}
+                (None, _) => responses.push("NAK".into()),
+            }
+        } else if common.is_empty() || multi_ack != MultiAck::None {
+            responses.push("NAK".into());
+        }
+        for response in responses {
+            writer.write_all(response.as_bytes()).await?;
+        }
+        writer.flush().await?;
+        if done {
+            return Ok(Some(common));
+        }
+        if stateless {
+            return Ok(None);
+        }
+    }
+}
+
+/// Build a pack with everything reachable from `wants` but not from `common`,
+/// sending it over `tx` in chunks as it gets written.
+fn build_pack(
+    repo_path: &Path,
+    wants: &[ObjectId],
+    common: &[ObjectId],
+    tx: mpsc::Sender<Vec<u8>>,
+) -> Result<(), git2::Error> {
+    let repo = git2::Repository::open_bare(repo_path)?;
+    let mut builder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+    for want in wants {
+        let object = repo.find_object(git2::Oid::from_bytes(want.as_bytes())?, None)?;
+        if object.kind() == Some(git2::ObjectType::Tag) {
+            builder.insert_object(object.id(), None)?;
+        }
+        match object.peel_to_commit() {
+            Ok(commit) => walk.push(commit.id())?,
+            Err(_) => builder.insert_recursive(object.peel(git2::ObjectType::Any)?.id(), None)?,
+        }
+    }
+    for have in common {
+        // haves which aren't commits can't be part of the walk.
+        let _ = walk.hide(git2::Oid::from_bytes(have.as_bytes())?);
+    }
+    builder.insert_walk(&mut walk)?;
+    builder.foreach(|chunk| tx.blocking_send(chunk.to_vec()).is_ok())?;
+    Ok(())
+}
+
+/// Stream the pack for `wants` to the client, multiplexed according to `side_band`.
+pub(super) async fn send_pack<W>(
+    writer: &mut W,
+    repo_path: PathBuf,
+    wants: Vec<ObjectId>,
+    common: Vec<ObjectId>,
+    side_band: SideBand,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel(16);
+    let packer = tokio::task::spawn_blocking(move || build_pack(&repo_path, &wants, &common, tx));
+    while let Some(chunk) = rx.recv().await {
+        match side_band {
+            SideBand::None => writer.write_all(&chunk).await?,
+            SideBand::Small | SideBand::Large => {
+                for data in chunk.chunks(side_band.max_payload()) {
+                    gix_packetline::encode::band_to_write(
+                        gix_packetline::Channel::Data,
+                        data,
+                        &mut *writer,
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+    let result = packer.await.expect("pack builder panicked");
+    if side_band != SideBand::None {
+        if let Err(e) = &result {
+            gix_packetline::encode::band_to_write(
+                gix_packetline::Channel::Error,
+                format!("failed to build pack: {}", e.message()).as_bytes(),
+                &mut *writer,
+            )
+            .await?;
+        }
+        gix_packetline::encode::flush_to_write(&mut *writer).await?;
+    }
+    writer.flush().await?;
+    result.map_err(Error::FailedToPack)
+}
+
+/// Serve a protocol v0/v1 fetch once the refs were advertised. A `stateless`
+/// context only gets a single request of git's `--stateless-rpc` mode, as used
+/// by the smart HTTP transport.
+pub(crate) async fn upload_pack<R, W>(
+    reader: R,
+    writer: W,
+    ctx: &ServiceContext,
+) -> Result<(), Error>
+where
+    R: futures::AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = gix_packetline::Writer::new(writer).text_mode();
+    let refs = list_refs(&ctx.repo.to_thread_local());
+    let advertised = allowed_wants(&tell_client(refs, writer.inner_mut()).await?);
+    let mut reader = StreamingPeekableIter::new(reader, &[PacketLineRef::Flush], false);
+    let wants = match read_wants(&mut reader, &advertised).await {
+        Ok(wants) => wants,
+        Err(e) => {
+            if let Error::InvalidWant(want) = &e {
+                gix_packetline::encode::error_to_write(
+                    format!("upload-pack: not our ref {want}").as_bytes(),
+                    writer.inner_mut(),
+                )
+                .await?;
+            }
+            return Err(e);
+        }
+    };
+
+    // Client early exited without wanting anything.
+    if wants.wants.is_empty() {
+        return Ok(());
+    }
+
+    let Some(common) = negotiate(
+        &mut reader,
+        &mut writer,
+        &ctx.repo,
+        wants.multi_ack,
+        ctx.stateless,
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+    send_pack(
+        writer.inner_mut(),
+        ctx.repo_path.clone(),
+        wants.wants,
+        common,
+        wants.side_band,
+    )
+    .await
+}
@@ -0,0 +1,134 @@
+//! Server-side syntax highlighting, as classes for `/highlight.css` to style.
+
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use axum::http::header;
+use axum::response::IntoResponse;
+use clru::CLruCache;
+use syntect::html::{line_tokens_to_classed_spans, ClassStyle};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+/// Blobs bigger than this are shown as they are, highlighting them would take
+/// too long.
+const MAX_HIGHLIGHTED_SIZE: usize = 512 * 1024;
+/// How many highlighted blobs to keep around.
+const CACHE_SIZE: usize = 256;
+/// So scopes like `comment.line` don't run into the pages' own classes.
+const PREFIX: &str = "hl-";
+const STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: PREFIX };
+const THEME: &str = "InspiredGitHub";
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// Highlighted lines by blob and the name of the syntax they're highlighted as,
+/// as the same blob tends to be shown over and over again.
+type Cache = CLruCache<(git2::Oid, String), Arc<Vec<String>>>;
+static CACHE: LazyLock<Mutex<Cache>> =
+    LazyLock::new(|| Mutex::new(CLruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())));
+
+/// `GET /highlight.css`, the colours of the classes highlighted code comes with.
+pub(crate) async fn stylesheet() -> impl IntoResponse {
+    static CSS: LazyLock<String> = LazyLock::new(|| {
+        let themes = syntect::highlighting::ThemeSet::load_defaults();
+        syntect::html::css_for_theme_with_class_style(&themes.themes[THEME], STYLE).unwrap()
+    });
+    ([(header::CONTENT_TYPE, "text/css")], CSS.as_str())
+}
+
+/// The syntax of the file at `path` with `content`, going by its
+/// `linguist-language` attribute, its extension or name, or its shebang, in
+/// that order.
+///
+/// Attributes come from the index, which bare repositories only have once
+/// they've been given one.
+pub(crate) fn syntax(
+    repo: &git2::Repository,
+    path: &str,
+    content: &[u8],
+) -> &'static SyntaxReference {
+    let by_attribute = || {
+        let flags = git2::AttrCheckFlags::INDEX_ONLY | git2::AttrCheckFlags::NO_SYSTEM;
+        let language = repo
+            .get_attr(Path::new(path), "linguist-language", flags)
+            .ok()??;
+        SYNTAXES
+            .find_syntax_by_name(language)
+            .or_else(|| SYNTAXES.find_syntax_by_token(language))
+    };
+    let by_name = || {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        // some syntaxes go by whole names, like `Makefile`.
+        SYNTAXES.find_syntax_by_extension(name).or_else(|| {
+            let extension = Path::new(name).extension()?.to_str()?;
+            SYNTAXES.find_syntax_by_extension(extension)
+        })
+    };
+    let by_shebang = || {
+        let first_line = content.split(|&b| b == b'\n').next()?;
+        SYNTAXES.find_syntax_by_first_line(std::str::from_utf8(first_line).ok()?)
+    };
+    by_attribute()
+        .or_else(by_name)
+        .or_else(by_shebang)
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text())
+}
+
+/// The lines of `blob` as HTML, highlighted as `syntax`. Every line closes the
+/// tags it opens, so they can go into rows of their own.
+///
+/// Missing for blobs that aren't text or are too big to bother with.
+pub(crate) fn highlight(blob: &git2::Blob, syntax: &SyntaxReference) -> Option<Arc<Vec<String>>> {
+    if blob.is_binary() || blob.size() > MAX_HIGHLIGHTED_SIZE {
+        return None;
+    }
+    let key = (blob.id(), syntax.name.clone());
+    if let Some(lines) = CACHE.lock().unwrap().get(&key) {
+        return Some(lines.clone());
+    }
+    let lines = match highlight_lines(&String::from_utf8_lossy(blob.content()), syntax) {
+        Ok(lines) => Arc::new(lines),
+        Err(e) => {
+            eprintln!("Couldn't highlight {} as {}: {e:?}", key.0, key.1);
+            return None;
+        }
+    };
+    CACHE.lock().unwrap().put(key, lines.clone());
+    Some(lines)
+}
+
+fn highlight_lines(text: &str, syntax: &SyntaxReference) -> Result<Vec<String>, syntect::Error> {
+    let mut parser = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut lines = vec![];
+    for line in LinesWithEndings::from(text) {
+        // the syntaxes want their newlines, the lines we show don't have them.
+        let ops = parser.parse_line(line, &SYNTAXES)?;
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        let ops: Vec<_> = ops
+            .into_iter()
+            .map(|(i, op)| (i.min(content.len()), op))
+            .collect();
+
+        // pick up where the last line left off.
+        let mut html: String = stack.as_slice().iter().map(|&scope| span(scope)).collect();
+        let (spans, _) = line_tokens_to_classed_spans(content, &ops, STYLE, &mut stack)?;
+        html.push_str(&spans);
+        html.push_str(&"</span>".repeat(stack.len()));
+        lines.push(html);
+    }
+    Ok(lines)
+}
+
+/// The opening tag for `scope`, with the classes syntect would give it.
+fn span(scope: Scope) -> String {
+    let classes: Vec<String> = scope
+        .build_string()
+        .split('.')
+        .map(|atom| format!("{PREFIX}{atom}"))
+        .collect();
+    format!("<span class=\"{}\">", classes.join(" "))
+}
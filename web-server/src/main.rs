@@ -6,24 +6,41 @@ use std::sync::Arc;
 use axum::Json;
 use axum::{routing, Router};
 use clap::Parser;
+use frontend::BlobReq;
 
-use repositories::CommitLogReq;
-use russh::keys::ssh_key::rand_core::OsRng;
+use repositories::{CommitLogReq, TreeReq};
 use russh::server::Server as _;
+use russh::{MethodKind, MethodSet};
+use smart_http::InfoRefsReq;
 use ssh::SshServer;
 use tokio::fs::DirBuilder;
 use tokio_stream::StreamExt;
+use tower_http::decompression::RequestDecompressionLayer;
 
+mod access;
 mod entities;
 mod frontend;
+mod git;
+mod highlight;
 mod repositories;
+mod resolve;
+mod smart_http;
 mod ssh;
+mod users;
 
 /// Webserver component for the code forge.
 #[derive(clap::Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     data_dir: PathBuf,
+    /// The archive formats `git archive --remote` may ask for, out of tar,
+    /// tar.gz (or tgz), tar.zst and zip.
+    #[arg(long, value_delimiter = ',', default_value = "tar,tar.gz,zip")]
+    upload_archive_formats: Vec<repositories::ArchiveFormat>,
+    /// How many MiB the archives cached under `archives/` may take up, past
+    /// which the ones sent longest ago get evicted.
+    #[arg(long, default_value_t = 1024)]
+    archive_cache_mib: u64,
 }
 
 fn main() {
@@ -36,17 +53,19 @@ fn main() {
 }
 
 async fn datadir_init(data_dir: &Path) {
-    match DirBuilder::new()
-        .recursive(true)
-        .create(data_dir.join("repositories/"))
-        .await
-    {
-        Ok(_) => {}
-        Err(e) => panic!(
-            "Failed to create {}/repositories/. Error: {:?}",
-            data_dir.to_string_lossy(),
-            e
-        ),
+    for dir in ["archives/", "repositories/", "ssh/", "users/"] {
+        match DirBuilder::new()
+            .recursive(true)
+            .create(data_dir.join(dir))
+            .await
+        {
+            Ok(_) => {}
+            Err(e) => panic!(
+                "Failed to create {}/{dir}. Error: {:?}",
+                data_dir.to_string_lossy(),
+                e
+            ),
+        }
     }
 }
 
@@ -86,10 +105,63 @@ async fn async_main() {
                 let f = f.clone();
                 move |axum::extract::Path(name): axum::extract::Path<String>| async move { f.repositories(&name).await }
             }))
+            .route("/highlight.css", routing::get(highlight::stylesheet))
             .route("/r/{entity}/{repo}", routing::get({
                 let f = f.clone();
                 move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>, axum::extract::Query(req): axum::extract::Query<CommitLogReq>| async move { f.repository(&entity, &repo, &req).await }
             }))
+            .route("/r/{entity}/{repo}/tree/{rev}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, rev)): axum::extract::Path<(String, String, String)>| async move { f.tree(&entity, &repo, &rev, "").await }
+            }))
+            .route("/r/{entity}/{repo}/tree/{rev}/{*path}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, rev, path)): axum::extract::Path<(String, String, String, String)>| async move { f.tree(&entity, &repo, &rev, &path).await }
+            }))
+            .route("/r/{entity}/{repo}/blob/{rev}/{*path}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, rev, path)): axum::extract::Path<(String, String, String, String)>, axum::extract::Query(req): axum::extract::Query<BlobReq>| async move { f.blob(&entity, &repo, &rev, &path, &req).await }
+            }))
+            .route("/r/{entity}/{repo}/raw/{rev}/{*path}", routing::get({
+                let args = args.clone();
+                move |axum::extract::Path((entity, repo, rev, path)): axum::extract::Path<(String, String, String, String)>| async move { repositories::Blob::raw(&args, &entity, &repo, &rev, &path).await }
+            }))
+            .route("/r/{entity}/{repo}/blame/{rev}/{*path}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, rev, path)): axum::extract::Path<(String, String, String, String)>| async move { f.blame(&entity, &repo, &rev, &path).await }
+            }))
+            .route("/r/{entity}/{repo}/archive/{*file}", routing::get({
+                let args = args.clone();
+                move |axum::extract::Path((entity, repo, file)): axum::extract::Path<(String, String, String)>| async move { repositories::archive(&args, &entity, &repo, &file).await }
+            }))
+            .route("/r/{entity}/{repo}/commit/{oid}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, oid)): axum::extract::Path<(String, String, String)>| async move { f.commit(&entity, &repo, &oid).await }
+            }))
+            .route("/r/{entity}/{repo}/compare/{*spec}", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo, spec)): axum::extract::Path<(String, String, String)>| async move { f.compare(&entity, &repo, &spec).await }
+            }))
+            .route("/r/{entity}/{repo}/branches", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>| async move { f.branches(&entity, &repo).await }
+            }))
+            .route("/r/{entity}/{repo}/tags", routing::get({
+                let f = f.clone();
+                move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>| async move { f.tags(&entity, &repo).await }
+            }))
+            .route("/r/{entity}/{repo}/info/refs", routing::get({
+                let args = args.clone();
+                move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>, axum::extract::Query(req): axum::extract::Query<InfoRefsReq>, headers: axum::http::HeaderMap| async move { smart_http::info_refs(&args, &entity, &repo, &req, &headers).await }
+            }))
+            .route("/r/{entity}/{repo}/git-upload-pack", routing::post({
+                let args = args.clone();
+                move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>, headers: axum::http::HeaderMap, body: axum::body::Body| async move { smart_http::upload_pack(&args, &entity, &repo, &headers, body).await }
+            }).layer(RequestDecompressionLayer::new()))
+            .route("/r/{entity}/{repo}/git-receive-pack", routing::post({
+                let args = args.clone();
+                move |axum::extract::Path((entity, repo)): axum::extract::Path<(String, String)>, headers: axum::http::HeaderMap, body: axum::body::Body| async move { smart_http::receive_pack(&args, &entity, &repo, &headers, body).await }
+            }).layer(RequestDecompressionLayer::new()))
             .route(
                 "/api/entities",
                 routing::get({
@@ -102,7 +174,7 @@ async fn async_main() {
                 routing::get({
                     let args = args.clone();
                     move |axum::extract::Path(name): axum::extract::Path<String>| async move {
-                        Json(entities::Entity::repos(&args, &name).await)
+                        entities::Entity::repos(&args, &name).await.map(Json)
                     }
                 }),
             )
@@ -115,19 +187,71 @@ async fn async_main() {
                         repositories::CommitLog::commit_log(&args, &name, &repo, &req).await.map(Json)
                     }
                 }),
-             );
+             )
+            .route(
+                "/api/{entity}/{repo}/commits/{oid}",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo, oid)): axum::extract::Path<(String, String, String)>| async move {
+                        repositories::CommitDetail::commit(&args, &name, &repo, &oid).await.map(Json)
+                    }
+                }),
+            )
+            .route(
+                "/api/{entity}/{repo}/blame/{rev}/{*path}",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo, rev, path)): axum::extract::Path<(String, String, String, String)>| async move {
+                        repositories::Blame::json(&args, &name, &repo, &rev, &path).await
+                    }
+                }),
+            )
+            .route(
+                "/api/{entity}/{repo}/compare/{*spec}",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo, spec)): axum::extract::Path<(String, String, String)>| async move {
+                        repositories::Compare::compare(&args, &name, &repo, &spec).await.map(Json)
+                    }
+                }),
+            )
+            .route(
+                "/api/{entity}/{repo}/branches",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo)): axum::extract::Path<(String, String)>| async move {
+                        repositories::Branches::branches(&args, &name, &repo).await.map(Json)
+                    }
+                }),
+            )
+            .route(
+                "/api/{entity}/{repo}/tags",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo)): axum::extract::Path<(String, String)>| async move {
+                        repositories::Tags::tags(&args, &name, &repo).await.map(Json)
+                    }
+                }),
+            )
+            .route(
+                "/api/{entity}/{repo}/tree",
+                routing::get({
+                    let args = args.clone();
+                    move |axum::extract::Path((name, repo)): axum::extract::Path<(String, String)>,
+                          axum::extract::Query(req): axum::extract::Query<TreeReq>| async move {
+                        repositories::Tree::tree(&args, &name, &repo, &req).await.map(Json)
+                    }
+                }),
+            );
     let web_server = axum::serve(
         tokio::net::TcpListener::bind("[::1]:4000").await.unwrap(),
         app,
     );
-    let mut ssh_server = SshServer::new(args.data_dir.clone());
+    let mut ssh_server = SshServer::new(args.data_dir.clone(), args.upload_archive_formats.clone());
     let ssh_server = ssh_server.run_on_address(
         Arc::new(russh::server::Config {
-            keys: vec![russh::keys::PrivateKey::random(
-                &mut OsRng,
-                russh::keys::Algorithm::Ed25519,
-            )
-            .unwrap()],
+            keys: ssh::host_keys(&args.data_dir),
+            methods: MethodSet::from(&[MethodKind::PublicKey][..]),
             ..Default::default()
         }),
         "[::1]:4022",
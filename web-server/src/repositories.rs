@@ -1,77 +1,324 @@
 use axum::http::StatusCode;
-use git2::Oid;
 
+use crate::access::{self, Role, ANONYMOUS};
+use crate::resolve::RepoName;
 use crate::Args;
 
+pub(crate) use archive::{archive, chunks, Archive, Format as ArchiveFormat};
+pub(crate) use blame::Blame;
+pub(crate) use blob::Blob;
+pub(crate) use commit::CommitDetail;
+pub(crate) use compare::Compare;
+pub(crate) use log::{CommitLog, CommitLogReq};
+pub(crate) use readme::Readme;
+pub(crate) use refs::{Branches, Tags};
+pub(crate) use tree::{Tree, TreeReq};
+
+mod archive;
+mod blame;
+mod blob;
+mod commit;
+mod compare;
+mod diff;
+mod log;
+mod markdown;
+mod readme;
+mod refs;
+mod tree;
+
+/// Open `entity/repo_name` for reading on behalf of the web.
+pub(crate) async fn open(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+) -> Result<git2::Repository, StatusCode> {
+    let name = RepoName::new(entity, repo_name)?;
+    access::require(&args.data_dir, ANONYMOUS, &name, Role::Read).await?;
+    git2::Repository::open_bare(name.path(&args.data_dir).await?).map_err(|e| match e.code() {
+        git2::ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        e => panic!("Couldn't open repo {name}, got unexpected error: {e:?}"),
+    })
+}
+
+/// The status for a revision git couldn't make sense of: 400 when it isn't a
+/// revision at all, 404 when it just doesn't exist.
+fn rev_error(e: git2::Error) -> StatusCode {
+    match e.code() {
+        git2::ErrorCode::InvalidSpec | git2::ErrorCode::Ambiguous => StatusCode::BAD_REQUEST,
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// The status for git failing on what should be there, like an object that's
+/// missing from the repository.
+fn internal_error(e: git2::Error) -> StatusCode {
+    eprintln!("Unexpected git error: {e:?}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Find the commit `rev` points at, or the one of `HEAD` without a `rev`.
+fn find_commit<'r>(
+    repo: &'r git2::Repository,
+    rev: Option<&str>,
+) -> Result<git2::Commit<'r>, StatusCode> {
+    match rev {
+        Some(rev) => repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit()),
+        None => repo.head().and_then(|head| head.peel_to_commit()),
+    }
+    .map_err(rev_error)
+}
+
+/// Split `rev` and `path` as they come out of a URL like
+/// `/tree/{rev}/{*path}` anew, as refs like `feature/x` have slashes of their
+/// own: the revision is the longest leading part of the two that resolves to a
+/// commit, the rest is the path. Without one that does, they stay as they are.
+pub(crate) async fn split_rev(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    rev: &str,
+    path: &str,
+) -> Result<(String, String), StatusCode> {
+    let repo = open(args, entity, repo_name).await?;
+    Ok(split_rev_in(&repo, rev, path))
+}
+
+/// [`split_rev`] in an open `repo`.
+///
+/// Only refs have names that go on past `rev`, so rather than resolving every
+/// leading part, the refs get listed once and just the parts naming one get
+/// tried, longest first.
+fn split_rev_in(repo: &git2::Repository, rev: &str, path: &str) -> (String, String) {
+    let path = path.trim_matches('/');
+    let spec = format!("{rev}/{path}");
+    let ends_in_spec = |name: &str| {
+        name.len() > rev.len()
+            && spec
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    let mut splits: Vec<usize> = match repo.references() {
+        Ok(mut references) => references
+            .names()
+            .filter_map(Result::ok)
+            .flat_map(|name| {
+                // the ways a revision can name a ref, see gitrevisions(7).
+                ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"]
+                    .into_iter()
+                    .filter_map(move |namespace| name.strip_prefix(namespace))
+            })
+            .filter(|&name| ends_in_spec(name))
+            .map(str::len)
+            .collect(),
+        Err(_) => vec![],
+    };
+    splits.sort_unstable_by(|a, b| b.cmp(a));
+    splits.dedup();
+    match splits
+        .into_iter()
+        .find(|&i| find_commit(repo, Some(&spec[..i])).is_ok())
+    {
+        Some(i) => (
+            spec[..i].to_owned(),
+            spec[i..].trim_start_matches('/').to_owned(),
+        ),
+        None => (rev.to_owned(), path.to_owned()),
+    }
+}
+
+/// Have attribute lookups in `repo` go by the `.gitattributes` files in `tree`,
+/// through an index made up of it. Bare repositories don't have an index or a
+/// work tree of their own for attributes to come from.
+fn use_attributes_from(repo: &git2::Repository, tree: &git2::Tree) -> Result<(), git2::Error> {
+    let mut index = git2::Index::new()?;
+    index.read_tree(tree)?;
+    repo.set_index(&mut index)
+}
+
+/// Split the message of `commit` into its first line and the rest.
+fn split_message(commit: &git2::Commit) -> (String, String) {
+    let message = commit.message().unwrap_or("(empty commit message)");
+    let [header, body @ ..]: &[&str] = &message.split('\n').collect::<Vec<_>>()[..] else {
+        unreachable!()
+    }; // body is empty in the case where there's no new line
+    (header.to_string(), body.join("\n"))
+}
+
+/// Who authored or committed a commit, and when.
 #[derive(serde::Serialize)]
-pub(crate) struct CommitLog {
-    pub commits: Vec<Commit>,
+pub(crate) struct Signature {
+    name: String,
+    email: String,
+    /// Seconds since the unix epoch.
+    time: i64,
+    /// The offset from UTC in minutes, as recorded in the commit.
+    offset: i32,
+    /// The time in the timezone it was recorded in, like `2024-01-02 13:04:05 +0100`.
+    date: String,
+}
+
+impl From<git2::Signature<'_>> for Signature {
+    fn from(value: git2::Signature<'_>) -> Self {
+        let when = value.when();
+        let date = gix::date::Time::new(when.seconds(), when.offset_minutes() * 60)
+            .format(gix::date::time::format::ISO8601);
+        Self {
+            name: String::from_utf8_lossy(value.name_bytes()).into_owned(),
+            email: String::from_utf8_lossy(value.email_bytes()).into_owned(),
+            time: when.seconds(),
+            offset: when.offset_minutes(),
+            date,
+        }
+    }
+}
+
+/// A key and value from the trailer block at the end of a commit message,
+/// like `Signed-off-by: A U Thor <author@example.com>`.
+#[derive(serde::Serialize)]
+pub(crate) struct Trailer {
+    key: String,
+    value: String,
 }
 
 #[derive(serde::Serialize)]
 pub(crate) struct Commit {
+    commit_id: String,
+    tree_id: String,
+    parents: Vec<String>,
+    author: Signature,
+    committer: Signature,
     message_header: String,
     message_body: String,
-    commit_id: String,
+    trailers: Vec<Trailer>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub(crate) struct CommitLogReq {
-    #[serde(default)]
-    pub(crate) rev: Option<String>,
-    #[serde(default)]
-    pub(crate) increment: i32,
+impl From<&git2::Commit<'_>> for Commit {
+    fn from(commit: &git2::Commit<'_>) -> Self {
+        let (message_header, message_body) = split_message(commit);
+        let trailers = match git2::message_trailers_bytes(commit.message_bytes()) {
+            Ok(trailers) => trailers
+                .iter()
+                .map(|(key, value)| Trailer {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    value: String::from_utf8_lossy(value).into_owned(),
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+        Self {
+            commit_id: commit.id().to_string(),
+            tree_id: commit.tree_id().to_string(),
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+            author: commit.author().into(),
+            committer: commit.committer().into(),
+            message_header,
+            message_body,
+            trailers,
+        }
+    }
 }
 
-impl CommitLog {
-    pub(crate) async fn commit_log(
-        args: &Args,
-        entity: &str,
-        repo_name: &str,
-        req: &CommitLogReq,
-    ) -> Result<CommitLog, StatusCode> {
-        let repo = git2::Repository::open_bare(
-            args.data_dir
-                .join(format!("repositories/{entity}/{repo_name}")),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A repository with a commit on `main`, `feature/x`, `release/1.0` and
+    /// `docs`, along with a tag `blob/v1` of a blob.
+    fn repo() -> (tempfile::TempDir, git2::Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        let signature = git2::Signature::new(
+            "Alice",
+            "alice@example.com",
+            &git2::Time::new(1_700_000_000, 0),
         )
-        .map_err(|e| match e.code() {
-            git2::ErrorCode::NotFound => StatusCode::NOT_FOUND,
-            e => {
-                panic!("Couldn't open repo {entity}/{repo_name}, got unexpected error: {e:?}")
-            }
-        })?;
-        let mut walk = repo.revwalk().unwrap();
-
-        match &req.rev {
-            Some(v) => walk
-                .push(
-                    repo.find_commit(Oid::from_str(v).unwrap())
-                        .map_err(|_| StatusCode::NOT_FOUND)?
-                        .id(),
-                )
-                .unwrap(),
-            None => walk
-                .push(repo.head().unwrap().peel_to_commit().unwrap().id())
-                .unwrap(),
+        .unwrap();
+        let blob = repo.blob(b"hello\n").unwrap();
+        let commit = {
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("README", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            repo.commit(None, &signature, &signature, "first", &tree, &[])
+                .unwrap()
+        };
+        for name in ["main", "feature/x", "release/1.0"] {
+            repo.reference(&format!("refs/heads/{name}"), commit, false, "")
+                .unwrap();
         }
+        repo.reference("refs/tags/docs", commit, false, "").unwrap();
+        repo.reference("refs/tags/blob/v1", blob, false, "")
+            .unwrap();
+        (dir, repo)
+    }
 
-        let messages: Vec<Commit> = walk
-            .skip(req.increment.try_into().unwrap())
-            .take(10)
-            .map(|oid| {
-                let commit = repo.find_commit(oid.unwrap()).unwrap();
-                let message = commit.message().unwrap_or("(empty commit message)");
-                let [header, body @ ..]: &[&str] = &message.split('\n').collect::<Vec<_>>()[..]
-                else {
-                    unreachable!()
-                }; // body is empty in the case where there's no new line
-                Commit {
-                    message_header: header.to_string(),
-                    message_body: body.join("\n"),
-                    commit_id: format!("{}", commit.id()),
-                }
-            })
-            .collect();
-        Ok(CommitLog { commits: messages })
+    fn pair(rev: &str, path: &str) -> (String, String) {
+        (rev.to_owned(), path.to_owned())
+    }
+
+    #[test]
+    fn splits_refs_with_slashes() {
+        let (_dir, repo) = repo();
+        assert_eq!(
+            split_rev_in(&repo, "feature", "x/README"),
+            pair("feature/x", "README")
+        );
+        assert_eq!(split_rev_in(&repo, "feature", "x"), pair("feature/x", ""));
+        assert_eq!(split_rev_in(&repo, "feature", "/x/"), pair("feature/x", ""));
+        assert_eq!(
+            split_rev_in(&repo, "release", "1.0/a/b/c"),
+            pair("release/1.0", "a/b/c")
+        );
+        assert_eq!(
+            split_rev_in(&repo, "refs", "heads/feature/x/README"),
+            pair("refs/heads/feature/x", "README")
+        );
+        assert_eq!(
+            split_rev_in(&repo, "heads", "feature/x/README"),
+            pair("heads/feature/x", "README")
+        );
+    }
+
+    #[test]
+    fn keeps_paths_named_like_refs() {
+        let (_dir, repo) = repo();
+        assert_eq!(
+            split_rev_in(&repo, "main", "README"),
+            pair("main", "README")
+        );
+        // `main/docs` isn't a ref, even though `docs` is.
+        assert_eq!(
+            split_rev_in(&repo, "main", "docs/README"),
+            pair("main", "docs/README")
+        );
+        assert_eq!(
+            split_rev_in(&repo, "main", "feature/x/README"),
+            pair("main", "feature/x/README")
+        );
+        // neither is a ref that only starts like the path.
+        assert_eq!(
+            split_rev_in(&repo, "feature", "xy/README"),
+            pair("feature", "xy/README")
+        );
+    }
+
+    #[test]
+    fn keeps_unresolvable_revs() {
+        let (_dir, repo) = repo();
+        assert_eq!(
+            split_rev_in(&repo, "nope", "README"),
+            pair("nope", "README")
+        );
+        assert_eq!(split_rev_in(&repo, "nope", ""), pair("nope", ""));
+        assert_eq!(
+            split_rev_in(&repo, "feature", "y/README"),
+            pair("feature", "y/README")
+        );
+        // a ref has to end up at a commit to be the revision.
+        assert_eq!(
+            split_rev_in(&repo, "blob", "v1/README"),
+            pair("blob", "v1/README")
+        );
     }
 }
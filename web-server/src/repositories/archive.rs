@@ -0,0 +1,837 @@
+//! Archives of a tree, the way `git archive` makes them.
+//!
+//! Archives get cached by the tree they're of, so the same release keeps
+//! downloading as the same bytes even if the compressors change how they
+//! compress. Other than the tree, what's in one only depends on the prefix and
+//! the commit, whose time every entry has and whose details go into
+//! `export-subst` files, which is what the rest of the key is made of. That way
+//! it's known without looking at anything in the tree, so cached archives are
+//! sent without walking it.
+//!
+//! The cache is kept to `--archive-cache-mib`, evicting the archives sent
+//! longest ago, which is when a cached one was last made or sent.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::Args;
+
+/// How much of an archive gets sent at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    pub(crate) const ALL: [Format; 4] = [Format::Tar, Format::TarGz, Format::TarZst, Format::Zip];
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::TarGz => "tar.gz",
+            Format::TarZst => "tar.zst",
+            Format::Zip => "zip",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Tar => "application/x-tar",
+            Format::TarGz => "application/gzip",
+            Format::TarZst => "application/zstd",
+            Format::Zip => "application/zip",
+        }
+    }
+
+    /// Split a file name like `v1.0.tar.gz` into `v1.0` and its format.
+    fn split(name: &str) -> Option<(&str, Format)> {
+        // `tar` is a suffix of neither of the others, so any order works.
+        Format::ALL.into_iter().find_map(|format| {
+            let rest = name.strip_suffix(format.extension())?.strip_suffix('.')?;
+            (!rest.is_empty()).then_some((rest, format))
+        })
+    }
+}
+
+/// Formats go by their extension, or `tgz` like in git.
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "tgz" => Ok(Format::TarGz),
+            name => Format::ALL
+                .into_iter()
+                .find(|format| format.extension() == name)
+                .ok_or_else(|| format!("unknown archive format '{name}'")),
+        }
+    }
+}
+
+enum EntryKind {
+    Directory,
+    File { executable: bool, subst: bool },
+    Symlink,
+}
+
+struct Entry {
+    /// Where it goes in the archive, without the prefix.
+    path: String,
+    kind: EntryKind,
+    id: git2::Oid,
+}
+
+/// Everything that goes into an archive, ready to be written out.
+pub(crate) struct Archive<'r> {
+    repo: &'r git2::Repository,
+    commit: Option<git2::Commit<'r>>,
+    /// What every path in the archive starts with, like `project-v1.0/`.
+    prefix: String,
+    /// Seconds since the unix epoch, the modification time of every entry.
+    mtime: i64,
+    entries: Vec<Entry>,
+}
+
+/// The `export-*` attributes of the paths in a tree, from its `.gitattributes`.
+///
+/// These come from gix rather than libgit2, which never matches directory
+/// patterns like `vendor/` in bare repositories.
+struct Attributes<'g> {
+    stack: gix::AttributeStack<'g>,
+    outcome: gix::attrs::search::Outcome,
+}
+
+impl Attributes<'_> {
+    /// Whether `name` is set for the entry at `path`.
+    fn is_set(&mut self, path: &str, directory: bool, name: &str) -> io::Result<bool> {
+        let mode = match directory {
+            true => gix::index::entry::Mode::DIR,
+            false => gix::index::entry::Mode::FILE,
+        };
+        self.outcome.reset();
+        self.stack
+            .at_path(path, Some(mode))?
+            .matching_attributes(&mut self.outcome);
+        Ok(self.outcome.iter_selected().any(|matched| {
+            matched.assignment.name.as_str() == name
+                && matched.assignment.state == gix::attrs::StateRef::Set
+        }))
+    }
+}
+
+impl<'r> Archive<'r> {
+    /// Work out what an archive of `tree` has in it, going by its attributes.
+    /// Archives of a `commit` are from its time, and get its details
+    /// substituted into `export-subst` files, others are from right now.
+    ///
+    /// Given any `paths`, only what's at them goes in, along with the
+    /// directories leading up to them. They're taken literally, not as globs.
+    pub(crate) fn new(
+        repo: &'r git2::Repository,
+        tree: &git2::Tree,
+        commit: Option<git2::Commit<'r>>,
+        prefix: String,
+        paths: &[String],
+    ) -> io::Result<Self> {
+        let gix_repo = gix::open(repo.path()).map_err(io::Error::other)?;
+        let tree_id = gix::ObjectId::from_bytes_or_panic(tree.id().as_bytes());
+        let index = gix_repo
+            .index_from_tree(&tree_id)
+            .map_err(io::Error::other)?;
+        let stack = gix_repo
+            .attributes_only(
+                &index,
+                gix::worktree::stack::state::attributes::Source::IdMapping,
+            )
+            .map_err(io::Error::other)?;
+        let outcome = stack.selected_attribute_matches(["export-ignore", "export-subst"]);
+        let mut attributes = Attributes { stack, outcome };
+        let mut entries = vec![];
+        // like git, the prefix gets a directory of its own.
+        if prefix.ends_with('/') {
+            entries.push(Entry {
+                path: String::new(),
+                kind: EntryKind::Directory,
+                id: tree.id(),
+            });
+        }
+        let paths: Vec<&str> = paths
+            .iter()
+            .map(|path| path.trim_matches('/'))
+            .filter(|path| !path.is_empty() && *path != ".")
+            .collect();
+        walk(repo, tree, "", &paths, &mut attributes, &mut entries)?;
+        if let Some(path) = paths
+            .iter()
+            .find(|&&path| !entries.iter().any(|entry| is_in(&entry.path, path)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("pathspec '{path}' did not match any files"),
+            ));
+        }
+        let mtime = match &commit {
+            Some(commit) => commit.time().seconds(),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64),
+        };
+        Ok(Archive {
+            repo,
+            commit,
+            prefix,
+            mtime,
+            entries,
+        })
+    }
+
+    /// The contents of the file `entry`, with any placeholders substituted.
+    fn content(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let blob = self.repo.find_blob(entry.id).map_err(io::Error::other)?;
+        Ok(match (&entry.kind, &self.commit) {
+            (EntryKind::File { subst: true, .. }, Some(commit)) => {
+                substitute(blob.content(), commit)
+            }
+            _ => blob.content().to_vec(),
+        })
+    }
+
+    /// Write the archive out in `format`.
+    pub(crate) fn write(&self, format: Format, out: impl Write) -> io::Result<()> {
+        match format {
+            Format::Tar => self.write_tar(out).map(drop),
+            Format::TarGz => {
+                // the default header has no name or time in it, like `gzip -n`.
+                let gz = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+                self.write_tar(gz)?.finish().map(drop)
+            }
+            Format::TarZst => {
+                let zst = zstd::Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                self.write_tar(zst)?.finish().map(drop)
+            }
+            Format::Zip => self.write_zip(out),
+        }
+    }
+
+    fn write_tar<W: Write>(&self, out: W) -> io::Result<W> {
+        let mut tar = tar::Builder::new(out);
+        for entry in &self.entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(self.mtime.max(0) as u64);
+            header.set_username("root")?;
+            header.set_groupname("root")?;
+            let path = format!("{}{}", self.prefix, entry.path);
+            match entry.kind {
+                EntryKind::Directory => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o775);
+                    header.set_size(0);
+                    let path = format!("{}/", path.trim_end_matches('/'));
+                    tar.append_data(&mut header, path, io::empty())?;
+                }
+                EntryKind::File { executable, .. } => {
+                    let content = self.content(entry)?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(if executable { 0o775 } else { 0o664 });
+                    header.set_size(content.len() as u64);
+                    tar.append_data(&mut header, path, &content[..])?;
+                }
+                EntryKind::Symlink => {
+                    let target = self.content(entry)?;
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    let target = String::from_utf8_lossy(&target);
+                    tar.append_link(&mut header, path, target.as_ref())?;
+                }
+            }
+        }
+        tar.into_inner()
+    }
+
+    fn write_zip(&self, out: impl Write) -> io::Result<()> {
+        let mut zip = zip::ZipWriter::new_stream(out);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip_time(self.mtime, self.commit.as_ref()));
+        for entry in &self.entries {
+            let path = format!("{}{}", self.prefix, entry.path);
+            match entry.kind {
+                EntryKind::Directory => {
+                    let path = format!("{}/", path.trim_end_matches('/'));
+                    zip.add_directory(path, options.unix_permissions(0o775))?;
+                }
+                EntryKind::File { executable, .. } => {
+                    let mode = if executable { 0o775 } else { 0o664 };
+                    zip.start_file(path, options.unix_permissions(mode))?;
+                    zip.write_all(&self.content(entry)?)?;
+                }
+                EntryKind::Symlink => {
+                    let target = String::from_utf8_lossy(&self.content(entry)?).into_owned();
+                    zip.add_symlink(path, target, options.unix_permissions(0o777))?;
+                }
+            }
+        }
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+/// `GET /r/{entity}/{repo}/archive/{rev}.{format}`, an archive of `rev`, from
+/// the cache if it's been made before.
+pub(crate) async fn archive(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    file_name: &str,
+) -> Result<Response, StatusCode> {
+    let (rev, format) = Format::split(file_name).ok_or(StatusCode::NOT_FOUND)?;
+    let repo = super::open(args, entity, repo_name).await?;
+    let commit_id = super::find_commit(&repo, Some(rev))?.id();
+    let name = format!("{repo_name}-{}", rev.replace('/', "-"));
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_owned()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}.{}\"", format.extension()),
+        ),
+    ];
+
+    let (tx, rx) = mpsc::channel(16);
+    let cache = Cache {
+        dir: args.data_dir.join("archives"),
+        limit: args.archive_cache_mib * 1024 * 1024,
+    };
+    tokio::task::spawn_blocking(move || {
+        let out = chunks(tx);
+        if let Err(e) = send(&repo, commit_id, format!("{name}/"), format, &cache, out) {
+            // a client that went away doesn't need telling about.
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("Couldn't archive {commit_id}: {e:?}");
+            }
+        }
+    });
+    let body = ReceiverStream::new(rx).map(Ok::<_, std::convert::Infallible>);
+    Ok((headers, Body::from_stream(body)).into_response())
+}
+
+/// Where archives get cached, and how many bytes of them there may be.
+struct Cache {
+    dir: PathBuf,
+    limit: u64,
+}
+
+impl Cache {
+    /// Where the archive of `commit` gets cached in `format`, see the module
+    /// docs.
+    fn path(&self, commit: &git2::Commit, prefix: &str, format: Format) -> PathBuf {
+        let key = format!("{prefix}\0{}", commit.id());
+        let key = git2::Oid::hash_object(git2::ObjectType::Blob, key.as_bytes()).unwrap();
+        self.dir
+            .join(commit.tree_id().to_string())
+            .join(format!("{key}.{}", format.extension()))
+    }
+
+    /// Remove the archives sent longest ago until the rest fit in the limit.
+    ///
+    /// Other requests may be evicting at the same time, so anything that's
+    /// already gone is skipped.
+    fn evict(&self) -> io::Result<()> {
+        let mut archives = vec![];
+        let mut total = 0;
+        for tree in std::fs::read_dir(&self.dir)? {
+            let Ok(files) = std::fs::read_dir(tree?.path()) else {
+                continue;
+            };
+            for file in files {
+                let path = file?.path();
+                // archives still being written aren't in the cache yet.
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    continue;
+                }
+                let Ok(metadata) = std::fs::metadata(&path) else {
+                    continue;
+                };
+                total += metadata.len();
+                archives.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+        archives.sort();
+        for (_, len, path) in archives {
+            if total <= self.limit {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => total -= len,
+            }
+            // the tree's directory goes with its last archive.
+            let _ = std::fs::remove_dir(path.parent().unwrap());
+        }
+        Ok(())
+    }
+}
+
+/// Write the archive of `commit_id` to `out`, from the cache if it's there,
+/// or made and cached if it isn't.
+fn send(
+    repo: &git2::Repository,
+    commit_id: git2::Oid,
+    prefix: String,
+    format: Format,
+    cache: &Cache,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let commit = repo.find_commit(commit_id).map_err(io::Error::other)?;
+    let cache_path = cache.path(&commit, &prefix, format);
+    if let Ok(mut cached) = std::fs::File::open(&cache_path) {
+        // it's been sent just now as far as eviction goes.
+        let _ = cached.set_modified(SystemTime::now());
+        io::copy(&mut cached, &mut out)?;
+        return out.flush();
+    }
+    let tree = commit.tree().map_err(io::Error::other)?;
+    let archive = Archive::new(repo, &tree, Some(commit), prefix, &[])?;
+
+    // requests for the same archive at the same time each write their own.
+    static TEMPORARIES: AtomicU64 = AtomicU64::new(0);
+    let temporary = cache_path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TEMPORARIES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(cache_path.parent().unwrap())?;
+    let file = std::fs::File::create(&temporary)?;
+    let written = (|| {
+        let mut tee = Tee(out, file);
+        archive.write(format, &mut tee)?;
+        tee.flush()?;
+        tee.1.sync_all()
+    })();
+    // it only shows up in the cache once it's complete.
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e);
+    }
+    std::fs::rename(&temporary, cache_path)?;
+    if let Err(e) = cache.evict() {
+        eprintln!("Couldn't evict cached archives: {e:?}");
+    }
+    Ok(())
+}
+
+/// Whether `path` is `dir` or somewhere in it.
+fn is_in(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Collect the entries of `tree` at `dir` that are in `paths`, or all of them
+/// without any, leaving out the ones with `export-ignore`.
+fn walk(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    dir: &str,
+    paths: &[&str],
+    attributes: &mut Attributes,
+    entries: &mut Vec<Entry>,
+) -> io::Result<()> {
+    for entry in tree.iter() {
+        let path = format!("{dir}{}", String::from_utf8_lossy(entry.name_bytes()));
+        let mode = entry.filemode();
+        let wanted = paths.is_empty() || paths.iter().any(|&wanted| is_in(&path, wanted));
+        // directories on the way to one of the paths go in only if it's there.
+        let leading = mode == 0o040000 && paths.iter().any(|&wanted| is_in(wanted, &path));
+        if !wanted && !leading {
+            continue;
+        }
+        let directory = matches!(mode, 0o040000 | 0o160000);
+        if attributes.is_set(&path, directory, "export-ignore")? {
+            continue;
+        }
+        let kind = match mode {
+            0o040000 => {
+                entries.push(Entry {
+                    path: path.clone(),
+                    kind: EntryKind::Directory,
+                    id: entry.id(),
+                });
+                let count = entries.len();
+                let subtree = repo.find_tree(entry.id()).map_err(io::Error::other)?;
+                let paths = if wanted { &[][..] } else { paths };
+                walk(
+                    repo,
+                    &subtree,
+                    &format!("{path}/"),
+                    paths,
+                    attributes,
+                    entries,
+                )?;
+                if !wanted && entries.len() == count {
+                    entries.pop();
+                }
+                continue;
+            }
+            // like git, submodules are just empty directories.
+            0o160000 => EntryKind::Directory,
+            0o120000 => EntryKind::Symlink,
+            mode => EntryKind::File {
+                executable: mode == 0o100755,
+                subst: attributes.is_set(&path, false, "export-subst")?,
+            },
+        };
+        entries.push(Entry {
+            path,
+            kind,
+            id: entry.id(),
+        });
+    }
+    Ok(())
+}
+
+/// A writer sending everything written to it on to `tx`, a chunk at a time.
+pub(crate) fn chunks(tx: mpsc::Sender<Vec<u8>>) -> impl Write {
+    io::BufWriter::with_capacity(CHUNK_SIZE, Chunks(tx))
+}
+
+/// Sends everything written to it on as chunks, best buffered.
+struct Chunks(mpsc::Sender<Vec<u8>>);
+
+impl Write for Chunks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes everything to both of its writers.
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// The last modified time zip wants, which is in the commit's local time.
+fn zip_time(mtime: i64, commit: Option<&git2::Commit>) -> zip::DateTime {
+    let offset = commit.map_or(0, |commit| commit.time().offset_minutes() * 60);
+    let date = gix::date::Time::new(mtime, offset)
+        .format(gix::date::time::CustomFormat::new("%Y %m %d %H %M %S"));
+    let parts: Vec<u16> = date.split(' ').filter_map(|n| n.parse().ok()).collect();
+    let &[year, month, day, hour, minute, second] = &parts[..] else {
+        return zip::DateTime::default();
+    };
+    // zip only has room for every other second.
+    zip::DateTime::from_date_and_time(
+        year,
+        month as u8,
+        day as u8,
+        hour as u8,
+        minute as u8,
+        second as u8 & !1,
+    )
+    .unwrap_or_default()
+}
+
+/// Expand the `$Format:...$` placeholders in `content` with the details of
+/// `commit`, like `git archive` does for `export-subst` files.
+fn substitute(content: &[u8], commit: &git2::Commit) -> Vec<u8> {
+    const START: &[u8] = b"$Format:";
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.windows(START.len()).position(|w| w == START) {
+        let after = &rest[start + START.len()..];
+        let Some(end) = after.iter().position(|&b| b == b'$') else {
+            break;
+        };
+        out.extend_from_slice(&rest[..start]);
+        out.extend_from_slice(pretty(&String::from_utf8_lossy(&after[..end]), commit).as_bytes());
+        rest = &after[end + 1..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// `format` with its placeholders filled in like `git log --pretty=format:`
+/// would. Only the more common placeholders are known, the others stay as they
+/// are.
+fn pretty(format: &str, commit: &git2::Commit) -> String {
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        match placeholder(rest, commit) {
+            Some((expansion, len)) => {
+                out.push_str(&expansion);
+                rest = &rest[len..];
+            }
+            None => out.push('%'),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The expansion of the placeholder `spec` starts with, and how long it is.
+fn placeholder(spec: &str, commit: &git2::Commit) -> Option<(String, usize)> {
+    use gix::date::time::format;
+
+    let short = |oid: git2::Oid| oid.to_string()[..7].to_owned();
+    let join = |ids: Vec<String>| ids.join(" ");
+    let expansion = match spec.get(..1)? {
+        "H" => commit.id().to_string(),
+        "h" => short(commit.id()),
+        "T" => commit.tree_id().to_string(),
+        "t" => short(commit.tree_id()),
+        "P" => join(commit.parent_ids().map(|id| id.to_string()).collect()),
+        "p" => join(commit.parent_ids().map(short).collect()),
+        "s" => commit.summary().unwrap_or_default().to_owned(),
+        "b" => commit.body().unwrap_or_default().to_owned(),
+        "B" => commit.message().unwrap_or_default().to_owned(),
+        "n" => "\n".to_owned(),
+        "%" => "%".to_owned(),
+        who @ ("a" | "c") => {
+            let signature = match who {
+                "a" => commit.author(),
+                _ => commit.committer(),
+            };
+            let when = signature.when();
+            let time = gix::date::Time::new(when.seconds(), when.offset_minutes() * 60);
+            let expansion = match spec.get(1..2)? {
+                "n" => String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+                "e" => String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+                "d" => time.format(format::DEFAULT),
+                "t" => when.seconds().to_string(),
+                "i" => time.format(format::ISO8601),
+                "I" => time.format(format::ISO8601_STRICT),
+                "s" => time.format(format::SHORT),
+                _ => return None,
+            };
+            return Some((expansion, 2));
+        }
+        _ => return None,
+    };
+    Some((expansion, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use super::*;
+
+    /// Write a tree of `files`, paths and their contents.
+    fn write_tree(repo: &git2::Repository, files: &[(&str, &str)]) -> git2::Oid {
+        let mut builder = repo.treebuilder(None).unwrap();
+        let mut dirs: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+        for &(path, content) in files {
+            match path.split_once('/') {
+                Some((dir, rest)) => dirs.entry(dir).or_default().push((rest, content)),
+                None => {
+                    let blob = repo.blob(content.as_bytes()).unwrap();
+                    builder.insert(path, blob, 0o100644).unwrap();
+                }
+            }
+        }
+        for (dir, files) in dirs {
+            builder
+                .insert(dir, write_tree(repo, &files), 0o040000)
+                .unwrap();
+        }
+        builder.write().unwrap()
+    }
+
+    /// Commit `files` with `message`, always at the same time.
+    fn commit(repo: &git2::Repository, files: &[(&str, &str)], message: &str) -> git2::Oid {
+        let signature = git2::Signature::new(
+            "Alice",
+            "alice@example.com",
+            &git2::Time::new(1_700_000_000, 60),
+        )
+        .unwrap();
+        let tree = repo.find_tree(write_tree(repo, files)).unwrap();
+        repo.commit(None, &signature, &signature, message, &tree, &[])
+            .unwrap()
+    }
+
+    fn bare_repo() -> (tempfile::TempDir, git2::Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        (dir, repo)
+    }
+
+    const FILES: &[(&str, &str)] = &[
+        (
+            ".gitattributes",
+            "secret.txt export-ignore\nvendor/ export-ignore\nVERSION export-subst\n",
+        ),
+        ("README", "$Format:%H$\n"),
+        ("VERSION", "$Format:%h %s by %an$\n"),
+        ("secret.txt", "hunter2\n"),
+        ("src/main.c", "int main;\n"),
+        ("vendor/lib.c", "int lib;\n"),
+    ];
+
+    fn archive<'r>(repo: &'r git2::Repository, commit: git2::Oid) -> Archive<'r> {
+        let commit = repo.find_commit(commit).unwrap();
+        let tree = commit.tree().unwrap();
+        Archive::new(repo, &tree, Some(commit), "p/".to_owned(), &[]).unwrap()
+    }
+
+    /// The whole archive of `commit` in `format`, as `send` writes it.
+    fn sent(repo: &git2::Repository, commit: git2::Oid, format: Format, cache: &Cache) -> Vec<u8> {
+        let mut out = vec![];
+        send(repo, commit, "p/".to_owned(), format, cache, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn leaves_out_export_ignore() {
+        let (_dir, repo) = bare_repo();
+        let commit = commit(&repo, FILES, "first");
+        let archive = archive(&repo, commit);
+        let paths: Vec<_> = archive
+            .entries
+            .iter()
+            .map(|entry| &entry.path[..])
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "",
+                ".gitattributes",
+                "README",
+                "VERSION",
+                "src",
+                "src/main.c"
+            ]
+        );
+    }
+
+    #[test]
+    fn substitutes_export_subst() {
+        let (_dir, repo) = bare_repo();
+        let commit = commit(&repo, FILES, "first");
+        let archive = archive(&repo, commit);
+        let content = |path| {
+            let entry = archive.entries.iter().find(|entry| entry.path == path);
+            String::from_utf8(archive.content(entry.unwrap()).unwrap()).unwrap()
+        };
+        let short = &commit.to_string()[..7];
+        assert_eq!(content("VERSION"), format!("{short} first by Alice\n"));
+        // only files with the attribute get substituted.
+        assert_eq!(content("README"), "$Format:%H$\n");
+    }
+
+    #[test]
+    fn keys_cache_by_tree_commit_prefix_and_format() {
+        let (_dir, repo) = bare_repo();
+        let first = repo.find_commit(commit(&repo, FILES, "first")).unwrap();
+        let again = repo.find_commit(commit(&repo, FILES, "again")).unwrap();
+        let cache = Cache {
+            dir: PathBuf::from("archives"),
+            limit: u64::MAX,
+        };
+        let path = cache.path(&first, "p/", Format::Tar);
+        assert_eq!(path, cache.path(&first, "p/", Format::Tar));
+        assert_eq!(
+            path.parent().unwrap(),
+            Path::new("archives").join(first.tree_id().to_string())
+        );
+        // the same tree in another commit has other times and substitutions.
+        assert_eq!(
+            path.parent(),
+            cache.path(&again, "p/", Format::Tar).parent()
+        );
+        assert_ne!(path, cache.path(&again, "p/", Format::Tar));
+        assert_ne!(path, cache.path(&first, "q/", Format::Tar));
+        assert_ne!(path, cache.path(&first, "p/", Format::Zip));
+    }
+
+    #[test]
+    fn repeat_requests_are_byte_identical() {
+        let (_dir, repo) = bare_repo();
+        let commit = commit(&repo, FILES, "first");
+        let data_dir = tempfile::tempdir().unwrap();
+        let cache = Cache {
+            dir: data_dir.path().join("archives"),
+            limit: u64::MAX,
+        };
+        for format in Format::ALL {
+            let made = sent(&repo, commit, format, &cache);
+            let cached = sent(&repo, commit, format, &cache);
+            assert_eq!(made, cached, "{format:?} from the cache");
+            std::fs::remove_dir_all(&cache.dir).unwrap();
+            let remade = sent(&repo, commit, format, &cache);
+            assert_eq!(made, remade, "{format:?} made again");
+        }
+    }
+
+    #[test]
+    fn evicts_archives_sent_longest_ago() {
+        let (_dir, repo) = bare_repo();
+        let old = commit(&repo, FILES, "old");
+        let new = commit(&repo, &FILES[1..], "new");
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut cache = Cache {
+            dir: data_dir.path().join("archives"),
+            limit: u64::MAX,
+        };
+        let path = |commit| {
+            let commit = repo.find_commit(commit).unwrap();
+            cache.path(&commit, "p/", Format::Tar)
+        };
+        let (old_path, new_path) = (path(old), path(new));
+        let set_sent = |path: &Path, time| {
+            let file = std::fs::File::open(path).unwrap();
+            file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs(time))
+                .unwrap();
+        };
+
+        sent(&repo, old, Format::Tar, &cache);
+        sent(&repo, new, Format::Tar, &cache);
+        set_sent(&old_path, 1);
+        set_sent(&new_path, 2);
+        // sending it from the cache makes the old one the newest.
+        sent(&repo, old, Format::Tar, &cache);
+        cache.limit = std::fs::metadata(&old_path).unwrap().len();
+        cache.evict().unwrap();
+        assert!(old_path.exists());
+        assert!(!new_path.exists());
+        assert!(!new_path.parent().unwrap().exists());
+
+        cache.limit = 0;
+        cache.evict().unwrap();
+        assert_eq!(std::fs::read_dir(&cache.dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+//! Who last changed each line of a file.
+//!
+//! Blame has to dig through history until every line is accounted for, which
+//! for long files with a lot of history takes longer than a request should be
+//! left waiting on its first byte. So files are blamed a chunk of lines at a
+//! time on a blocking thread, and every hunk is passed on as soon as it's known.
+
+use std::path::Path;
+
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::Signature;
+use crate::Args;
+
+/// How many lines get blamed at a time.
+const CHUNK_LINES: usize = 500;
+
+/// Consecutive lines last changed by the same commit.
+#[derive(serde::Serialize)]
+pub(crate) struct BlameHunk {
+    /// The line the hunk starts at, counting from 1.
+    pub start_line: usize,
+    /// The lines as they are in the blamed revision.
+    pub lines: Vec<String>,
+    pub commit_id: String,
+    pub message_header: String,
+    pub author: Signature,
+    /// Where the file was in that commit, which differs if it's been renamed
+    /// since.
+    pub path: String,
+    /// The first parent of that commit, to blame what was there before it.
+    /// Missing for root commits.
+    pub parent: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Blame {
+    /// The commit the revision resolved to.
+    pub commit_id: String,
+    pub path: String,
+    pub id: String,
+    /// Binary files aren't blamed, so they don't have any hunks.
+    pub binary: bool,
+    /// The hunks from the top of the file down, as they're found.
+    #[serde(skip)]
+    pub hunks: mpsc::Receiver<BlameHunk>,
+}
+
+impl Blame {
+    /// Start blaming the file at `path` in `rev`.
+    pub(crate) async fn blame(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Blame, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let path = path.trim_matches('/').to_owned();
+        let (commit_id, id, binary, lines) = {
+            let commit = super::find_commit(&repo, Some(rev))?;
+            let blob = super::blob::find_blob(&repo, &commit, &path)?;
+            let binary = blob.is_binary();
+            let lines: Vec<String> = if binary {
+                vec![]
+            } else {
+                String::from_utf8_lossy(blob.content())
+                    .lines()
+                    .map(str::to_owned)
+                    .collect()
+            };
+            (commit.id(), blob.id(), binary, lines)
+        };
+
+        let (tx, hunks) = mpsc::channel(16);
+        tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                if let Err(e) = blame_lines(&repo, commit_id, &path, &lines, &tx) {
+                    eprintln!("Couldn't blame {path} at {commit_id}: {e:?}");
+                }
+            }
+        });
+
+        Ok(Blame {
+            commit_id: commit_id.to_string(),
+            path,
+            id: id.to_string(),
+            binary,
+            hunks,
+        })
+    }
+
+    /// The blame of `path` in `rev` as JSON, with its `hunks` written out as
+    /// they come in.
+    pub(crate) async fn json(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Response, StatusCode> {
+        let (rev, path) = super::split_rev(args, entity, repo_name, rev, path).await?;
+        let blame = Blame::blame(args, entity, repo_name, &rev, &path).await?;
+        // everything but the hunks fits in the opening, which is left open for
+        // them to go at the end of.
+        let mut opening = serde_json::to_string(&blame).unwrap();
+        opening.pop();
+        opening.push_str(r#","hunks":["#);
+
+        let hunks = ReceiverStream::new(blame.hunks)
+            .enumerate()
+            .map(|(i, hunk)| {
+                let comma = if i == 0 { "" } else { "," };
+                format!("{comma}{}", serde_json::to_string(&hunk).unwrap())
+            });
+        let body = futures::stream::once(async { opening })
+            .chain(hunks)
+            .chain(futures::stream::once(async { "]}".to_owned() }))
+            .map(Ok::<_, std::convert::Infallible>);
+        Ok((
+            [(header::CONTENT_TYPE, "application/json")],
+            Body::from_stream(body),
+        )
+            .into_response())
+    }
+}
+
+/// Blame `lines`, the contents of `path` in `commit_id`, sending every hunk to
+/// `tx` once it's sure not to grow any further. Stops early once nobody's
+/// listening anymore.
+fn blame_lines(
+    repo: &git2::Repository,
+    commit_id: git2::Oid,
+    path: &str,
+    lines: &[String],
+    tx: &mpsc::Sender<BlameHunk>,
+) -> Result<(), git2::Error> {
+    // the last hunk of a chunk may well carry on into the next one.
+    let mut pending: Option<BlameHunk> = None;
+    for first in (1..=lines.len()).step_by(CHUNK_LINES) {
+        let last = (first + CHUNK_LINES - 1).min(lines.len());
+        let mut opts = git2::BlameOptions::new();
+        opts.newest_commit(commit_id).min_line(first).max_line(last);
+        let blame = repo.blame_file(Path::new(path), Some(&mut opts))?;
+
+        for hunk in blame.iter() {
+            let start = hunk.final_start_line().max(first);
+            let end = (hunk.final_start_line() + hunk.lines_in_hunk()).min(last + 1);
+            if start >= end {
+                continue;
+            }
+            let hunk_lines = &lines[start - 1..end - 1];
+            let id = hunk.final_commit_id().to_string();
+
+            if let Some(pending) = pending.as_mut().filter(|pending| {
+                pending.commit_id == id && pending.start_line + pending.lines.len() == start
+            }) {
+                pending.lines.extend_from_slice(hunk_lines);
+                continue;
+            }
+
+            let commit = repo.find_commit(hunk.final_commit_id())?;
+            let (message_header, _) = super::split_message(&commit);
+            let next = BlameHunk {
+                start_line: start,
+                lines: hunk_lines.to_vec(),
+                commit_id: id,
+                message_header,
+                author: Signature::from(commit.author()),
+                path: hunk
+                    .path()
+                    .map_or_else(|| path.to_owned(), |p| p.to_string_lossy().into_owned()),
+                parent: commit.parent_ids().next().map(|parent| parent.to_string()),
+            };
+            if let Some(done) = pending.replace(next) {
+                if tx.blocking_send(done).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    if let Some(done) = pending {
+        let _ = tx.blocking_send(done);
+    }
+    Ok(())
+}
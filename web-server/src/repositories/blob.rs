@@ -0,0 +1,202 @@
+use std::convert::Infallible;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::markdown::{self, Links, Markdown};
+use crate::Args;
+
+/// Files bigger than this are only offered for download.
+pub(super) const MAX_RENDERED_SIZE: usize = 1024 * 1024;
+
+#[derive(serde::Serialize)]
+pub(crate) struct Blob {
+    /// The commit the revision resolved to.
+    pub commit_id: String,
+    pub path: String,
+    pub id: String,
+    pub size: usize,
+    /// Whether the contents look binary, going by the same heuristic as git.
+    pub binary: bool,
+    /// The lines of text files small enough to show.
+    pub lines: Option<Vec<String>>,
+    /// The same lines as syntax highlighted HTML, for files small enough.
+    pub highlighted: Option<Vec<String>>,
+    /// Markdown files rendered, for files small enough.
+    pub markdown: Option<Markdown>,
+}
+
+/// Find the blob at `path` in `commit`.
+pub(super) fn find_blob<'r>(
+    repo: &'r git2::Repository,
+    commit: &git2::Commit,
+    path: &str,
+) -> Result<git2::Blob<'r>, StatusCode> {
+    commit
+        .tree()
+        .map_err(super::internal_error)?
+        .get_path(Path::new(path.trim_matches('/')))
+        .and_then(|entry| entry.to_object(repo))
+        .map_err(|_| StatusCode::NOT_FOUND)?
+        .into_blob()
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+impl Blob {
+    pub(crate) async fn blob(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Blob, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let commit = super::find_commit(&repo, Some(rev))?;
+        let blob = find_blob(&repo, &commit, path)?;
+        let binary = blob.is_binary();
+        let lines = (!binary && blob.size() <= MAX_RENDERED_SIZE).then(|| {
+            String::from_utf8_lossy(blob.content())
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        });
+        let highlighted = lines.as_ref().and_then(|_| {
+            super::use_attributes_from(&repo, &commit.tree().unwrap()).ok()?;
+            let syntax = crate::highlight::syntax(&repo, path.trim_matches('/'), blob.content());
+            crate::highlight::highlight(&blob, syntax).map(|lines| lines.to_vec())
+        });
+        let markdown = lines
+            .as_ref()
+            .filter(|_| super::readme::is_markdown(path))
+            .map(|_| {
+                let path = path.trim_matches('/');
+                let links = Links {
+                    base: format!("/r/{entity}/{repo_name}"),
+                    rev,
+                    dir: path.rsplit_once('/').map_or("", |(dir, _)| dir),
+                    tree: &commit.tree().unwrap(),
+                };
+                markdown::render(&String::from_utf8_lossy(blob.content()), &links)
+            });
+        Ok(Blob {
+            commit_id: commit.id().to_string(),
+            path: path.trim_matches('/').to_owned(),
+            id: blob.id().to_string(),
+            size: blob.size(),
+            binary,
+            lines,
+            highlighted,
+            markdown,
+        })
+    }
+
+    /// The bytes of the blob at `path` in `rev`, as is, streamed.
+    ///
+    /// Text is always served as `text/plain`, and binary files only get a more
+    /// specific type if it's media that can't run scripts on our origin.
+    pub(crate) async fn raw(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: &str,
+        path: &str,
+    ) -> Result<Response, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let (rev, path) = super::split_rev_in(&repo, rev, path);
+        let (id, size) = {
+            let commit = super::find_commit(&repo, Some(&rev))?;
+            let entry = commit
+                .tree()
+                .map_err(super::internal_error)?
+                .get_path(Path::new(path.trim_matches('/')))
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            if entry.kind() != Some(git2::ObjectType::Blob) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            let (size, _) = repo
+                .odb()
+                .and_then(|odb| odb.read_header(entry.id()))
+                .map_err(super::internal_error)?;
+            (entry.id(), size)
+        };
+
+        let (binary_tx, binary_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::channel(16);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = send_raw(&repo, id, binary_tx, super::chunks(tx)) {
+                // a client that went away doesn't need telling about.
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    eprintln!("Couldn't send blob {id}: {e:?}");
+                }
+            }
+        });
+        // only dropped without an answer if the blob couldn't be read.
+        let binary = binary_rx
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let content_type = if binary {
+            let guess = mime_guess::from_path(&path).first_or_octet_stream();
+            let inert = matches!(guess.type_().as_str(), "image" | "audio" | "video" | "font")
+                && guess.subtype() != mime_guess::mime::SVG
+                || guess == mime_guess::mime::APPLICATION_PDF;
+            if inert {
+                guess.to_string()
+            } else {
+                mime_guess::mime::APPLICATION_OCTET_STREAM.to_string()
+            }
+        } else {
+            "text/plain; charset=utf-8".to_owned()
+        };
+        Ok((
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_LENGTH, size.to_string()),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            ],
+            Body::from_stream(ReceiverStream::new(rx).map(Ok::<_, Infallible>)),
+        )
+            .into_response())
+    }
+}
+
+/// How much of a blob git looks at to tell if it's binary.
+const BINARY_PEEK: usize = 8000;
+
+/// Write blob `id` to `out`, first telling `binary` whether it looks binary.
+///
+/// libgit2 can only stream loose objects, packed ones get inflated whole and
+/// are sent from memory.
+fn send_raw(
+    repo: &git2::Repository,
+    id: git2::Oid,
+    binary: oneshot::Sender<bool>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let odb = repo.odb().map_err(io::Error::other)?;
+    let object;
+    let mut reader: Box<dyn Read> = match odb.reader(id) {
+        Ok((reader, _, _)) => Box::new(reader),
+        Err(_) => {
+            object = odb.read(id).map_err(io::Error::other)?;
+            Box::new(object.data())
+        }
+    };
+    let mut peek = Vec::with_capacity(BINARY_PEEK);
+    reader
+        .by_ref()
+        .take(BINARY_PEEK as u64)
+        .read_to_end(&mut peek)?;
+    // the same test as git's: any NUL near the start.
+    if binary.send(peek.contains(&0)).is_err() {
+        return Ok(());
+    }
+    out.write_all(&peek)?;
+    io::copy(&mut reader, &mut out)?;
+    out.flush()
+}
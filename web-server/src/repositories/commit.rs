@@ -0,0 +1,38 @@
+use axum::http::StatusCode;
+
+use super::diff::Diff;
+use super::Commit;
+use crate::Args;
+
+#[derive(serde::Serialize)]
+pub(crate) struct CommitDetail {
+    #[serde(flatten)]
+    commit: Commit,
+    /// The changes against the first parent, or everything for a root commit.
+    diff: Diff,
+}
+
+impl CommitDetail {
+    pub(crate) async fn commit(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: &str,
+    ) -> Result<CommitDetail, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let commit = super::find_commit(&repo, Some(rev))?;
+        let tree = commit.tree().map_err(super::internal_error)?;
+        let parent_tree = commit
+            .parents()
+            .next()
+            .map(|parent| parent.tree())
+            .transpose()
+            .map_err(super::internal_error)?;
+        let diff = Diff::between(&repo, parent_tree.as_ref(), Some(&tree))
+            .map_err(super::internal_error)?;
+        Ok(CommitDetail {
+            commit: Commit::from(&commit),
+            diff,
+        })
+    }
+}
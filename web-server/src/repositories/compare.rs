@@ -0,0 +1,83 @@
+use axum::http::StatusCode;
+
+use super::diff::Diff;
+use super::log::Range;
+use super::Commit;
+use crate::Args;
+
+/// Comparing more commits than this only lists the newest.
+const MAX_COMMITS: usize = 250;
+
+#[derive(serde::Serialize)]
+pub(crate) struct Compare {
+    base: String,
+    head: String,
+    merge_base: Option<String>,
+    /// Whether this is `base...head`, which diffs from the merge base, rather
+    /// than `base..head`, which diffs from `base` itself.
+    three_dot: bool,
+    /// What's in `head` but not in `base`, newest first.
+    commits: Vec<Commit>,
+    /// Whether there were more commits than `commits` has.
+    truncated: bool,
+    diff: Diff,
+}
+
+impl Compare {
+    /// Compare the revisions in `spec`, which is `base...head` or `base..head`.
+    pub(crate) async fn compare(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        spec: &str,
+    ) -> Result<Compare, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let (base, head, three_dot) = match spec.split_once("...") {
+            Some((base, head)) => (base, head, true),
+            None => match spec.split_once("..") {
+                Some((base, head)) => (base, head, false),
+                None => return Err(StatusCode::BAD_REQUEST),
+            },
+        };
+        let base = super::find_commit(&repo, Some(base))?;
+        let head = super::find_commit(&repo, Some(head))?;
+        let merge_base = repo.merge_base(base.id(), head.id()).ok();
+
+        let mut commits = Range::between(base.id(), head.id())
+            .commits(&repo)
+            .map_err(super::internal_error)?;
+        let listed = commits
+            .by_ref()
+            .take(MAX_COMMITS)
+            .map(|commit| commit.map(|commit| Commit::from(&commit)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(super::internal_error)?;
+        let truncated = commits
+            .next()
+            .transpose()
+            .map_err(super::internal_error)?
+            .is_some();
+
+        // without any history in common, there's nothing better to diff from.
+        let from = match merge_base {
+            Some(merge_base) if three_dot => repo
+                .find_commit(merge_base)
+                .map_err(super::internal_error)?,
+            _ => base.clone(),
+        };
+        let from_tree = from.tree().map_err(super::internal_error)?;
+        let head_tree = head.tree().map_err(super::internal_error)?;
+        let diff = Diff::between(&repo, Some(&from_tree), Some(&head_tree))
+            .map_err(super::internal_error)?;
+
+        Ok(Compare {
+            base: base.id().to_string(),
+            head: head.id().to_string(),
+            merge_base: merge_base.map(|oid| oid.to_string()),
+            three_dot,
+            commits: listed,
+            truncated,
+            diff,
+        })
+    }
+}
@@ -0,0 +1,203 @@
+//! Diffs between two trees, as the commit and compare pages show them.
+
+use std::sync::Arc;
+
+#[derive(Default, serde::Serialize)]
+pub(crate) struct DiffStat {
+    files_changed: usize,
+    insertions: usize,
+    deletions: usize,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FileStatus {
+    Added,
+    Deleted,
+    Modified,
+    Renamed,
+    Copied,
+    Typechange,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LineKind {
+    Context,
+    Addition,
+    Deletion,
+    /// git's `\ No newline at end of file`.
+    NoNewline,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct DiffLine {
+    kind: LineKind,
+    old_lineno: Option<u32>,
+    new_lineno: Option<u32>,
+    content: String,
+    /// The content as syntax highlighted HTML, if its side of the file could
+    /// be highlighted.
+    highlighted: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Hunk {
+    /// The `@@ -1,2 +1,3 @@` line, including whatever context git put after it.
+    header: String,
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct FileDiff {
+    status: FileStatus,
+    /// Where the file was before, unless it was added.
+    old_path: Option<String>,
+    /// Where the file is now, unless it was deleted.
+    new_path: Option<String>,
+    binary: bool,
+    insertions: usize,
+    deletions: usize,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Diff {
+    stats: DiffStat,
+    files: Vec<FileDiff>,
+}
+
+fn lossy_path(file: git2::DiffFile) -> Option<String> {
+    file.path_bytes()
+        .map(|path| String::from_utf8_lossy(path).into_owned())
+}
+
+/// The highlighted lines of the `old` and `new` side of the file at `path`, for
+/// the sides that have any.
+fn highlight_sides(
+    repo: &git2::Repository,
+    path: Option<&str>,
+    old: git2::DiffFile,
+    new: git2::DiffFile,
+) -> [Option<Arc<Vec<String>>>; 2] {
+    // missing sides have zero ids, which won't be found.
+    let [old, new] = [old, new].map(|file| repo.find_blob(file.id()).ok());
+    let (Some(path), Some(blob)) = (path, new.as_ref().or(old.as_ref())) else {
+        return [None, None];
+    };
+    let syntax = crate::highlight::syntax(repo, path, blob.content());
+    [old, new].map(|blob| crate::highlight::highlight(&blob?, syntax))
+}
+
+impl Diff {
+    /// Diff `old` against `new`, where a missing tree counts as empty, with
+    /// renames and copies detected.
+    ///
+    /// Files are highlighted going by the attributes of `new`, or `old` if
+    /// that's all there is, which `repo` is left using.
+    pub(crate) fn between(
+        repo: &git2::Repository,
+        old: Option<&git2::Tree>,
+        new: Option<&git2::Tree>,
+    ) -> Result<Self, git2::Error> {
+        let mut diff = repo.diff_tree_to_tree(old, new, None)?;
+        diff.find_similar(None)?;
+        if let Some(tree) = new.or(old) {
+            super::use_attributes_from(repo, tree)?;
+        }
+
+        let mut stats = DiffStat::default();
+        let mut files = vec![];
+        for i in 0..diff.deltas().len() {
+            let Some(patch) = git2::Patch::from_diff(&diff, i)? else {
+                continue;
+            };
+            let delta = patch.delta();
+            let status = match delta.status() {
+                git2::Delta::Added => FileStatus::Added,
+                git2::Delta::Deleted => FileStatus::Deleted,
+                git2::Delta::Renamed => FileStatus::Renamed,
+                git2::Delta::Copied => FileStatus::Copied,
+                git2::Delta::Typechange => FileStatus::Typechange,
+                _ => FileStatus::Modified,
+            };
+            let old_path = match status {
+                FileStatus::Added => None,
+                _ => lossy_path(delta.old_file()),
+            };
+            let new_path = match status {
+                FileStatus::Deleted => None,
+                _ => lossy_path(delta.new_file()),
+            };
+            let binary = delta.flags().is_binary();
+            let [old_highlighted, new_highlighted] = match binary {
+                true => [None, None],
+                false => highlight_sides(
+                    repo,
+                    new_path.as_deref().or(old_path.as_deref()),
+                    delta.old_file(),
+                    delta.new_file(),
+                ),
+            };
+
+            let mut hunks = vec![];
+            for h in 0..patch.num_hunks() {
+                let (hunk, line_count) = patch.hunk(h)?;
+                let mut lines = vec![];
+                for l in 0..line_count {
+                    let line = patch.line_in_hunk(h, l)?;
+                    let kind = match line.origin() {
+                        '+' => LineKind::Addition,
+                        '-' => LineKind::Deletion,
+                        '=' | '>' | '<' => LineKind::NoNewline,
+                        _ => LineKind::Context,
+                    };
+                    let side = match kind {
+                        LineKind::Deletion => old_highlighted.as_ref().zip(line.old_lineno()),
+                        LineKind::Addition | LineKind::Context => {
+                            new_highlighted.as_ref().zip(line.new_lineno())
+                        }
+                        LineKind::NoNewline => None,
+                    };
+                    lines.push(DiffLine {
+                        kind,
+                        old_lineno: line.old_lineno(),
+                        new_lineno: line.new_lineno(),
+                        content: String::from_utf8_lossy(line.content())
+                            .trim_end_matches('\n')
+                            .to_owned(),
+                        highlighted: side
+                            .and_then(|(lines, lineno)| lines.get(lineno as usize - 1).cloned()),
+                    });
+                }
+                hunks.push(Hunk {
+                    header: String::from_utf8_lossy(hunk.header()).trim_end().to_owned(),
+                    old_start: hunk.old_start(),
+                    old_lines: hunk.old_lines(),
+                    new_start: hunk.new_start(),
+                    new_lines: hunk.new_lines(),
+                    lines,
+                });
+            }
+
+            let (_, insertions, deletions) = patch.line_stats()?;
+            stats.files_changed += 1;
+            stats.insertions += insertions;
+            stats.deletions += deletions;
+            files.push(FileDiff {
+                status,
+                old_path,
+                new_path,
+                binary,
+                insertions,
+                deletions,
+                hunks,
+            });
+        }
+        Ok(Diff { stats, files })
+    }
+}
@@ -0,0 +1,686 @@
+//! The commit log, a page at a time.
+//!
+//! Pages are found with cursors rather than offsets, so that going forward
+//! doesn't mean walking all of history up to the page again. A cursor forward
+//! holds the frontier of the walk, the commits it would visit next, and a
+//! cursor back holds the first commit of the page it came from. Frontiers only
+//! get wide on history with lots of merges going on at once, and past
+//! [`MAX_FRONTIER`] commits the cursor holds the last commit walked instead,
+//! which the next page walks up to again. Cursors are opaque to clients, as
+//! what they hold is bound to change.
+//!
+//! This walks history itself rather than with a `git2::Revwalk`, as libgit2
+//! walks all of it up front as soon as it's asked for any order, and can't be
+//! picked up again from a frontier. The order is by commit time like `git log`
+//! has it, so like there, commits with clocks gone wrong can show up out of
+//! place, or here, on more than one page. Ranges like `a..b` still cost a walk
+//! over all of the range for every page.
+//!
+//! Filtering happens as the walk goes, so pages are always full, except for
+//! the last one. Whether there's anything after a page is only known by looking
+//! for it, which for a filter that matches nothing more would mean walking
+//! all of the rest of history, so there's a cursor forward as long as the walk
+//! isn't done, and the last page can come out empty. Walks with `since` are
+//! done once they're past it.
+
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use axum::http::StatusCode;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use git2::Oid;
+
+use super::Commit;
+use crate::Args;
+
+const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_PAGE_SIZE: usize = 100;
+/// The most commits a cursor holds the frontier of the walk with.
+const MAX_FRONTIER: usize = 32;
+/// Goes up whenever what cursors hold changes, so older ones are turned down
+/// rather than misread.
+const CURSOR_VERSION: u8 = 1;
+const OID_LEN: usize = 20;
+
+#[derive(serde::Serialize)]
+pub(crate) struct CommitLog {
+    pub commits: Vec<Commit>,
+    /// The cursor for the page after this one, unless this is the last.
+    pub next: Option<String>,
+    /// The cursor for the page before this one, unless this is the first.
+    pub prev: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct CommitLogReq {
+    #[serde(default)]
+    pub(crate) rev: Option<String>,
+    /// Where to pick up, as `next` or `prev` of an earlier page of the same `rev`.
+    #[serde(default)]
+    pub(crate) cursor: Option<String>,
+    #[serde(default)]
+    pub(crate) per_page: Option<usize>,
+    #[serde(flatten)]
+    pub(crate) filter: LogFilter,
+}
+
+/// What to narrow the log down to, like `git log --follow -- <path>` and its
+/// `--author`, `--committer`, `--since`, `--until` and `--grep`. Empty values
+/// are the same as none, as that's what empty form fields give us.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub(crate) struct LogFilter {
+    /// Only commits that touch this file or directory, following files
+    /// through renames.
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// Only commits whose author's name or email contains this, ignoring case.
+    #[serde(default)]
+    pub(crate) author: Option<String>,
+    /// Only commits whose committer's name or email contains this, ignoring case.
+    #[serde(default)]
+    pub(crate) committer: Option<String>,
+    /// Only commits committed at or after this, in any format git takes,
+    /// like `2024-01-02` or `2 weeks ago`.
+    #[serde(default)]
+    pub(crate) since: Option<String>,
+    /// Only commits committed at or before this.
+    #[serde(default)]
+    pub(crate) until: Option<String>,
+    /// Only commits whose message contains this, ignoring case.
+    #[serde(default)]
+    pub(crate) grep: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Cursor {
+    /// The page the walk continues with from these commits, along with the
+    /// name the followed path had by then, if it got renamed.
+    After(Vec<Oid>, Option<String>),
+    /// The page the walk continues with after visiting this commit, for when
+    /// the frontier got too wide to hold.
+    Past(Oid),
+    /// The page that ends right before this commit.
+    Before(Oid),
+}
+
+impl Cursor {
+    /// The version, a byte for the kind of cursor, and what it holds: the
+    /// number of commits in the frontier, their ids, and the path for
+    /// [`Cursor::After`], or the id of the commit for the others.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CURSOR_VERSION];
+        match self {
+            Cursor::After(frontier, path) => {
+                bytes.push(b'a');
+                bytes.push(u8::try_from(frontier.len()).expect("frontiers are capped"));
+                frontier
+                    .iter()
+                    .for_each(|oid| bytes.extend_from_slice(oid.as_bytes()));
+                path.iter()
+                    .for_each(|path| bytes.extend_from_slice(path.as_bytes()));
+            }
+            Cursor::Past(last) => {
+                bytes.push(b'p');
+                bytes.extend_from_slice(last.as_bytes());
+            }
+            Cursor::Before(first) => {
+                bytes.push(b'b');
+                bytes.extend_from_slice(first.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let oid = |bytes: &[u8]| Oid::from_bytes(bytes).ok();
+        match bytes {
+            [CURSOR_VERSION, b'a', count, rest @ ..] => {
+                let count = usize::from(*count);
+                if count == 0 || count > MAX_FRONTIER || rest.len() < count * OID_LEN {
+                    return None;
+                }
+                let (frontier, path) = rest.split_at(count * OID_LEN);
+                let path = match path {
+                    [] => None,
+                    path => Some(std::str::from_utf8(path).ok()?.to_owned()),
+                };
+                Some(Cursor::After(
+                    frontier.chunks(OID_LEN).map(oid).collect::<Option<_>>()?,
+                    path,
+                ))
+            }
+            [CURSOR_VERSION, b'p', last @ ..] if last.len() == OID_LEN => {
+                Some(Cursor::Past(oid(last)?))
+            }
+            [CURSOR_VERSION, b'b', first @ ..] if first.len() == OID_LEN => {
+                Some(Cursor::Before(oid(first)?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = StatusCode;
+
+    fn from_str(s: &str) -> Result<Self, StatusCode> {
+        URL_SAFE_NO_PAD
+            .decode(s)
+            .ok()
+            .and_then(|bytes| Cursor::from_bytes(&bytes))
+            .ok_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&URL_SAFE_NO_PAD.encode(self.to_bytes()))
+    }
+}
+
+/// A [`LogFilter`], ready to match commits against.
+struct Matcher {
+    /// What the followed path is called at the point the walk is at.
+    path: Option<String>,
+    author: Option<String>,
+    committer: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    grep: Option<String>,
+}
+
+/// The lowercased `value`, unless it's missing or empty.
+fn needle(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .filter(|value| !value.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Whether `signature` contains `needle` in its name or email.
+fn signed_by(signature: git2::Signature, needle: &Option<String>) -> bool {
+    needle.as_ref().is_none_or(|needle| {
+        String::from_utf8_lossy(signature.name_bytes())
+            .to_lowercase()
+            .contains(needle)
+            || String::from_utf8_lossy(signature.email_bytes())
+                .to_lowercase()
+                .contains(needle)
+    })
+}
+
+impl Matcher {
+    fn new(filter: &LogFilter) -> Result<Self, StatusCode> {
+        let time = |value: &Option<String>| {
+            value
+                .as_deref()
+                .filter(|value| !value.is_empty())
+                .map(|value| gix::date::parse(value, Some(SystemTime::now())))
+                .transpose()
+                .map(|time| time.map(|time| time.seconds))
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        };
+        Ok(Matcher {
+            path: filter
+                .path
+                .as_deref()
+                .map(|path| path.trim_matches('/'))
+                .filter(|path| !path.is_empty())
+                .map(str::to_owned),
+            author: needle(&filter.author),
+            committer: needle(&filter.committer),
+            since: time(&filter.since)?,
+            until: time(&filter.until)?,
+            grep: needle(&filter.grep),
+        })
+    }
+
+    /// Whether `commit` makes it into the log. Commits need to come in the
+    /// order of the walk, as this is where renames get followed.
+    fn matches(&mut self, repo: &git2::Repository, commit: &git2::Commit) -> bool {
+        let committed = commit.committer().when().seconds();
+        self.touches_path(repo, commit)
+            && self.since.is_none_or(|since| committed >= since)
+            && self.until.is_none_or(|until| committed <= until)
+            && signed_by(commit.author(), &self.author)
+            && signed_by(commit.committer(), &self.committer)
+            && self.grep.as_ref().is_none_or(|grep| {
+                String::from_utf8_lossy(commit.message_bytes())
+                    .to_lowercase()
+                    .contains(grep)
+            })
+    }
+
+    /// Whether `commit` changed the path compared to each of its parents, the
+    /// way `git log -- <path>` has it.
+    fn touches_path(&mut self, repo: &git2::Repository, commit: &git2::Commit) -> bool {
+        let Some(path) = &self.path else {
+            return true;
+        };
+        let entry = |commit: &git2::Commit| {
+            commit
+                .tree()
+                .ok()?
+                .get_path(Path::new(path))
+                .ok()
+                .map(|entry| (entry.id(), entry.kind()))
+        };
+        let here = entry(commit);
+        let parents: Vec<_> = commit.parents().collect();
+        if parents.iter().any(|parent| entry(parent) == here) {
+            return false;
+        }
+
+        // a file that shows up out of nowhere might have been renamed from
+        // somewhere, which is what older commits will call it then.
+        if let ([parent], Some((_, Some(git2::ObjectType::Blob)))) = (&parents[..], here) {
+            if let Some(old_path) = renamed_from(repo, parent, commit, path) {
+                self.path = Some(old_path);
+            }
+        }
+        here.is_some() || !parents.is_empty()
+    }
+}
+
+/// The path `path` had in `parent` if `commit` renamed it.
+fn renamed_from(
+    repo: &git2::Repository,
+    parent: &git2::Commit,
+    commit: &git2::Commit,
+    path: &str,
+) -> Option<String> {
+    let mut diff = repo
+        .diff_tree_to_tree(Some(&parent.tree().ok()?), Some(&commit.tree().ok()?), None)
+        .ok()?;
+    diff.find_similar(None).ok()?;
+    diff.deltas()
+        .find(|delta| {
+            delta.status() == git2::Delta::Renamed
+                && delta.new_file().path() == Some(Path::new(path))
+        })?
+        .old_file()
+        .path()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// The commits `rev` selects, the way `git rev-list` takes it: a revision and
+/// its history, `a..b` for what's in `b` but not `a`, and `a...b` for what's in
+/// either but not both.
+pub(super) struct Range {
+    tips: Vec<Oid>,
+    hidden: Vec<Oid>,
+}
+
+impl Range {
+    /// `None` if there's no `rev` and `HEAD` doesn't have any commits yet.
+    fn parse(repo: &git2::Repository, rev: Option<&str>) -> Result<Option<Range>, StatusCode> {
+        let Some(rev) = rev else {
+            if repo
+                .head()
+                .is_err_and(|e| e.code() == git2::ErrorCode::UnbornBranch)
+            {
+                return Ok(None);
+            }
+            return Ok(Some(Range {
+                tips: vec![super::find_commit(repo, None)?.id()],
+                hidden: vec![],
+            }));
+        };
+
+        let spec = repo.revparse(rev).map_err(super::rev_error)?;
+        let commit = |object: Option<&git2::Object>| {
+            object
+                .ok_or(StatusCode::BAD_REQUEST)?
+                .peel_to_commit()
+                .map_err(super::rev_error)
+                .map(|commit| commit.id())
+        };
+        if spec.mode().contains(git2::RevparseMode::SINGLE) {
+            return Ok(Some(Range {
+                tips: vec![commit(spec.from())?],
+                hidden: vec![],
+            }));
+        }
+        let (from, to) = (commit(spec.from())?, commit(spec.to())?);
+        Ok(Some(
+            if spec.mode().contains(git2::RevparseMode::MERGE_BASE) {
+                Range {
+                    tips: vec![from, to],
+                    hidden: repo.merge_base(from, to).ok().into_iter().collect(),
+                }
+            } else {
+                Range {
+                    tips: vec![to],
+                    hidden: vec![from],
+                }
+            },
+        ))
+    }
+
+    /// What's in `head` but not in `base`, like `base..head`.
+    pub(super) fn between(base: Oid, head: Oid) -> Range {
+        Range {
+            tips: vec![head],
+            hidden: vec![base],
+        }
+    }
+
+    /// All of the range, newest first.
+    pub(super) fn commits<'r>(&self, repo: &'r git2::Repository) -> Result<Walk<'r>, git2::Error> {
+        let tips = self
+            .tips
+            .iter()
+            .map(|tip| repo.find_commit(*tip))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.walk(repo, &tips)
+    }
+
+    /// Walk the range from `tips` on rather than its own.
+    fn walk<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        tips: &[git2::Commit<'r>],
+    ) -> Result<Walk<'r>, git2::Error> {
+        // what's out of the range can only be told by walking all of the
+        // range, which libgit2 does right when it's asked to hide anything.
+        let members = if self.hidden.is_empty() {
+            None
+        } else {
+            let mut revwalk = repo.revwalk()?;
+            for tip in &self.tips {
+                revwalk.push(*tip)?;
+            }
+            for hidden in &self.hidden {
+                revwalk.hide(*hidden)?;
+            }
+            Some(revwalk.collect::<Result<_, _>>()?)
+        };
+        let mut walk = Walk {
+            repo,
+            queue: BinaryHeap::new(),
+            seen: HashSet::new(),
+            members,
+            since: None,
+            last: None,
+        };
+        for tip in tips {
+            walk.push(tip);
+        }
+        Ok(walk)
+    }
+}
+
+/// History, newest first by commit time, like `git log` without any ordering
+/// options.
+pub(super) struct Walk<'r> {
+    repo: &'r git2::Repository,
+    /// What's yet to visit, by commit time.
+    queue: BinaryHeap<(i64, Oid)>,
+    seen: HashSet<Oid>,
+    /// Every commit in the range, unless that's all of history.
+    members: Option<HashSet<Oid>>,
+    /// The commit time the walk stops at, if it stops before the root commits.
+    since: Option<i64>,
+    /// The commit visited last.
+    last: Option<Oid>,
+}
+
+impl<'r> Walk<'r> {
+    fn push(&mut self, commit: &git2::Commit) {
+        let member = self
+            .members
+            .as_ref()
+            .is_none_or(|members| members.contains(&commit.id()));
+        if member && self.seen.insert(commit.id()) {
+            self.queue
+                .push((commit.committer().when().seconds(), commit.id()));
+        }
+    }
+
+    /// The commit the walk visits next.
+    fn peek(&self) -> Option<Oid> {
+        self.queue.peek().map(|&(_, oid)| oid)
+    }
+
+    /// The cursor to pick the walk up from where it is, along with the name
+    /// the followed path has by now if it got renamed. `None` once it's done.
+    fn cursor(&self, path: Option<String>) -> Option<Cursor> {
+        let frontier = self.frontier();
+        match self.last {
+            _ if frontier.is_empty() => None,
+            Some(last) if frontier.len() > MAX_FRONTIER => Some(Cursor::Past(last)),
+            _ => Some(Cursor::After(frontier, path)),
+        }
+    }
+
+    /// What's left to visit, to pick the walk up from later.
+    fn frontier(&self) -> Vec<Oid> {
+        let mut frontier = self.queue.clone().into_sorted_vec();
+        frontier.reverse();
+        frontier
+            .into_iter()
+            .take_while(|&(time, _)| self.since.is_none_or(|since| time >= since))
+            .map(|(_, oid)| oid)
+            .collect()
+    }
+
+    /// Look `oid` up and queue its parents.
+    fn visit(&mut self, oid: Oid) -> Result<git2::Commit<'r>, git2::Error> {
+        let commit = self.repo.find_commit(oid)?;
+        for parent in commit.parent_ids() {
+            let parent = self.repo.find_commit(parent)?;
+            self.push(&parent);
+        }
+        Ok(commit)
+    }
+}
+
+impl<'r> Iterator for Walk<'r> {
+    type Item = Result<git2::Commit<'r>, git2::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &(time, _) = self.queue.peek()?;
+        // everything after is older still, but for clocks gone wrong.
+        if self.since.is_some_and(|since| time < since) {
+            self.queue.clear();
+            return None;
+        }
+        let (_, oid) = self.queue.pop()?;
+        self.last = Some(oid);
+        Some(self.visit(oid))
+    }
+}
+
+impl CommitLog {
+    pub(crate) async fn commit_log(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        req: &CommitLogReq,
+    ) -> Result<CommitLog, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let per_page = req
+            .per_page
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let cursor = req.cursor.as_deref().map(Cursor::from_str).transpose()?;
+        let Some(range) = Range::parse(&repo, req.rev.as_deref())? else {
+            return Ok(CommitLog {
+                commits: vec![],
+                next: None,
+                prev: None,
+            });
+        };
+
+        let mut matcher = Matcher::new(&req.filter)?;
+        let requested_path = matcher.path.clone();
+        // cursors only need to know where the path is at if that's elsewhere.
+        let renamed =
+            |path: Option<String>| path.filter(|path| Some(path) != requested_path.as_ref());
+
+        let (page, next, prev) = match &cursor {
+            Some(Cursor::Before(first)) => {
+                // there's no walking history backwards, so this walks up to
+                // `first` again, keeping the last page worth of commits.
+                let mut walk = range.commits(&repo).map_err(super::internal_error)?;
+                walk.since = matcher.since;
+                let mut page = VecDeque::with_capacity(per_page + 1);
+                let mut more_before = false;
+                let next = loop {
+                    if walk.peek() == Some(*first) {
+                        break walk.cursor(renamed(matcher.path.clone()));
+                    }
+                    let commit = walk
+                        .next()
+                        .ok_or(StatusCode::NOT_FOUND)?
+                        .map_err(super::internal_error)?;
+                    if !matcher.matches(&repo, &commit) {
+                        continue;
+                    }
+                    page.push_back(commit);
+                    if page.len() > per_page {
+                        page.pop_front();
+                        more_before = true;
+                    }
+                };
+                let prev = more_before.then(|| Cursor::Before(page[0].id()));
+                (Vec::from(page), next, prev)
+            }
+            after => {
+                let mut walk = match after {
+                    Some(Cursor::After(frontier, path)) => {
+                        if path.is_some() {
+                            matcher.path.clone_from(path);
+                        }
+                        // only cursors can get us commits that don't exist.
+                        let frontier = frontier
+                            .iter()
+                            .map(|oid| repo.find_commit(*oid))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(|_| StatusCode::BAD_REQUEST)?;
+                        range.walk(&repo, &frontier)
+                    }
+                    _ => range.commits(&repo),
+                }
+                .map_err(super::internal_error)?;
+                walk.since = matcher.since;
+                if let Some(Cursor::Past(last)) = after {
+                    // the walk got too wide for the cursor to hold where it
+                    // was, so it gets there again, following renames as it goes.
+                    loop {
+                        let commit = walk
+                            .next()
+                            .ok_or(StatusCode::BAD_REQUEST)?
+                            .map_err(super::internal_error)?;
+                        matcher.matches(&repo, &commit);
+                        if commit.id() == *last {
+                            break;
+                        }
+                    }
+                }
+                let page = walk
+                    .by_ref()
+                    .filter(|commit| match commit {
+                        Ok(commit) => matcher.matches(&repo, commit),
+                        Err(_) => true,
+                    })
+                    .take(per_page)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(super::internal_error)?;
+                let next = walk.cursor(renamed(matcher.path.clone()));
+                let prev = match (after, page.first()) {
+                    (Some(_), Some(first)) => Some(Cursor::Before(first.id())),
+                    _ => None,
+                };
+                (page, next, prev)
+            }
+        };
+
+        let commits = page.iter().map(Commit::from).collect();
+        Ok(CommitLog {
+            commits,
+            next: next.map(|cursor| cursor.to_string()),
+            prev: prev.map(|cursor| cursor.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; OID_LEN]).unwrap()
+    }
+
+    fn round_trip(cursor: Cursor) {
+        let encoded = cursor.to_string();
+        assert!(encoded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(encoded.parse::<Cursor>().unwrap(), cursor);
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        round_trip(Cursor::After(vec![oid(1)], None));
+        round_trip(Cursor::After(vec![oid(1), oid(2), oid(3)], None));
+        round_trip(Cursor::After(
+            vec![oid(1)],
+            Some("src/old name.rs".to_owned()),
+        ));
+        round_trip(Cursor::After(
+            vec![oid(7); MAX_FRONTIER],
+            Some("é:.".to_owned()),
+        ));
+        round_trip(Cursor::Past(oid(4)));
+        round_trip(Cursor::Before(oid(5)));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        let encode = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+        let after = |count: u8, oids: usize, path: &[u8]| {
+            let mut bytes = vec![CURSOR_VERSION, b'a', count];
+            bytes.extend(std::iter::repeat_n(1, oids * OID_LEN));
+            bytes.extend_from_slice(path);
+            encode(&bytes)
+        };
+        let with_oid = |version: u8, kind: u8, len: usize| {
+            let mut bytes = vec![version, kind];
+            bytes.extend(std::iter::repeat_n(1, len));
+            encode(&bytes)
+        };
+        let malformed = [
+            String::new(),
+            "not base64!".to_owned(),
+            format!("after.{}", oid(1)),
+            format!("before.{}", oid(1)),
+            Cursor::Before(oid(1)).to_string() + "=",
+            encode(&[CURSOR_VERSION]),
+            with_oid(CURSOR_VERSION + 1, b'b', OID_LEN),
+            with_oid(0, b'b', OID_LEN),
+            with_oid(CURSOR_VERSION, b'x', OID_LEN),
+            with_oid(CURSOR_VERSION, b'b', OID_LEN - 1),
+            with_oid(CURSOR_VERSION, b'b', OID_LEN + 1),
+            with_oid(CURSOR_VERSION, b'p', 0),
+            encode(&[CURSOR_VERSION, b'a']),
+            after(0, 0, b""),
+            after(2, 1, b""),
+            after(1, 0, b"path"),
+            after(MAX_FRONTIER as u8 + 1, MAX_FRONTIER + 1, b""),
+            after(1, 1, b"\xff\xfe"),
+        ];
+        for cursor in malformed {
+            assert_eq!(
+                cursor.parse::<Cursor>(),
+                Err(StatusCode::BAD_REQUEST),
+                "{cursor:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Markdown, like READMEs, rendered to HTML that's safe to put on our pages.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+/// What the ids in rendered documents start with, so they can't clash with the
+/// ones of the page around them.
+const ID_PREFIX: &str = "user-content-";
+
+#[derive(serde::Serialize)]
+pub(crate) struct Heading {
+    level: u8,
+    text: String,
+    /// The id of the heading, to link to it with.
+    anchor: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Markdown {
+    /// Sanitized, so anything the document had in the way of scripts is gone.
+    pub html: String,
+    /// Every heading in order, for a table of contents.
+    pub toc: Vec<Heading>,
+}
+
+/// Where the relative links of a document go.
+pub(super) struct Links<'a> {
+    /// Like `/r/{entity}/{repo}`.
+    pub base: String,
+    pub rev: &'a str,
+    /// The directory the document is in, which its links are relative to.
+    pub dir: &'a str,
+    /// The tree of `rev`, to tell links to files and directories apart.
+    pub tree: &'a git2::Tree<'a>,
+}
+
+/// Whether `url` starts with a scheme, like `https:` or `mailto:`.
+fn has_scheme(url: &str) -> bool {
+    url.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+impl Links<'_> {
+    /// Point `url` at our pages if it's relative: images at the raw file,
+    /// links at the blob or tree. Links within the document get their anchors
+    /// prefixed like the ids they go to.
+    fn rewrite(&self, url: &str, image: bool) -> String {
+        if let Some(anchor) = url.strip_prefix('#') {
+            return format!("#{ID_PREFIX}{anchor}");
+        }
+        if url.starts_with("//") || has_scheme(url) {
+            return url.to_owned();
+        }
+        let (path, suffix) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+
+        // absolute paths start at the root of the repository, like on GitHub.
+        let mut parts: Vec<&str> = match path.starts_with('/') {
+            true => vec![],
+            false => self
+                .dir
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .collect(),
+        };
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        let path = parts.join("/");
+        let directory = path.is_empty()
+            || self
+                .tree
+                .get_path(Path::new(&path))
+                .is_ok_and(|entry| entry.kind() == Some(git2::ObjectType::Tree));
+        let kind = match (image, directory) {
+            (true, _) => "raw",
+            (false, true) => "tree",
+            (false, false) => "blob",
+        };
+        let path = if path.is_empty() {
+            path
+        } else {
+            format!("/{path}")
+        };
+        format!("{}/{kind}/{}{path}{suffix}", self.base, self.rev)
+    }
+}
+
+/// The anchor for a heading saying `text`, the way GitHub makes them, with a
+/// number on the end for the ones `seen` before.
+fn anchor(text: &str, seen: &mut HashMap<String, usize>) -> String {
+    let slug: String = text
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect();
+    let count = seen.entry(slug.clone()).or_default();
+    *count += 1;
+    match *count {
+        1 => slug,
+        n => format!("{slug}-{}", n - 1),
+    }
+}
+
+/// Render the markdown in `source`, with relative links going where `links`
+/// says.
+pub(super) fn render(source: &str, links: &Links) -> Markdown {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut events = vec![];
+    let mut toc = vec![];
+    let mut seen = HashMap::new();
+    // where the heading we're in started, and its text so far.
+    let mut heading: Option<(usize, String)> = None;
+    for event in Parser::new_ext(source, options) {
+        let event = match event {
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: links.rewrite(&dest_url, false).into(),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Image {
+                link_type,
+                dest_url: links.rewrite(&dest_url, true).into(),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Heading { .. }) => {
+                heading = Some((events.len(), String::new()));
+                event
+            }
+            Event::Text(ref text) | Event::Code(ref text) => {
+                if let Some((_, heading)) = &mut heading {
+                    heading.push_str(text);
+                }
+                event
+            }
+            Event::End(TagEnd::Heading(level)) => {
+                if let Some((start, text)) = heading.take() {
+                    let anchor = anchor(&text, &mut seen);
+                    if let Event::Start(Tag::Heading { id, .. }) = &mut events[start] {
+                        *id = Some(anchor.clone().into());
+                    }
+                    toc.push(Heading {
+                        level: level as u8,
+                        text,
+                        anchor: format!("{ID_PREFIX}{anchor}"),
+                    });
+                }
+                event
+            }
+            event => event,
+        };
+        events.push(event);
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    let html = ammonia::Builder::default()
+        .add_tag_attributes("h1", &["id"])
+        .add_tag_attributes("h2", &["id"])
+        .add_tag_attributes("h3", &["id"])
+        .add_tag_attributes("h4", &["id"])
+        .add_tag_attributes("h5", &["id"])
+        .add_tag_attributes("h6", &["id"])
+        .id_prefix(Some(ID_PREFIX))
+        .clean(&html)
+        .to_string();
+    Markdown { html, toc }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use axum::http::StatusCode;
+
+use super::markdown::{self, Links, Markdown};
+use crate::Args;
+
+/// What READMEs go by, lowercased, in the order they're picked in.
+const NAMES: &[&str] = &[
+    "readme.md",
+    "readme.markdown",
+    "readme.rst",
+    "readme.txt",
+    "readme",
+];
+
+#[derive(serde::Serialize)]
+pub(crate) struct Readme {
+    pub path: String,
+    /// Markdown READMEs, rendered.
+    pub markdown: Option<Markdown>,
+    /// Any other kind, as is. There's no rendering reStructuredText, so it's
+    /// shown as the text it is.
+    pub text: Option<String>,
+}
+
+/// Whether the file at `path` is markdown, going by its extension.
+pub(super) fn is_markdown(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("md") || extension.eq_ignore_ascii_case("markdown")
+        })
+}
+
+impl Readme {
+    /// The README in the directory at `dir` of `rev`, or of `HEAD` without a
+    /// `rev`, if there is one small enough to show.
+    pub(crate) async fn readme(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        rev: Option<&str>,
+        dir: &str,
+    ) -> Result<Option<Readme>, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let commit = match super::find_commit(&repo, rev) {
+            Ok(commit) => commit,
+            // nothing's been pushed yet.
+            Err(_) if rev.is_none() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let root = commit.tree().unwrap();
+        let dir = dir.trim_matches('/');
+        let tree = if dir.is_empty() {
+            root.clone()
+        } else {
+            root.get_path(Path::new(dir))
+                .and_then(|entry| entry.to_object(&repo))
+                .map_err(|_| StatusCode::NOT_FOUND)?
+                .into_tree()
+                .map_err(|_| StatusCode::NOT_FOUND)?
+        };
+
+        let Some(entry) = NAMES.iter().find_map(|name| {
+            tree.iter().find(|entry| {
+                entry.kind() == Some(git2::ObjectType::Blob)
+                    && entry
+                        .name()
+                        .is_some_and(|entry| entry.eq_ignore_ascii_case(name))
+            })
+        }) else {
+            return Ok(None);
+        };
+        let blob = entry.to_object(&repo).unwrap().peel_to_blob().unwrap();
+        if blob.is_binary() || blob.size() > super::blob::MAX_RENDERED_SIZE {
+            return Ok(None);
+        }
+
+        let name = entry.name().unwrap();
+        let path = if dir.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{dir}/{name}")
+        };
+        let content = String::from_utf8_lossy(blob.content());
+        let (markdown, text) = if is_markdown(name) {
+            let links = Links {
+                base: format!("/r/{entity}/{repo_name}"),
+                rev: rev.unwrap_or("HEAD"),
+                dir,
+                tree: &root,
+            };
+            (Some(markdown::render(&content, &links)), None)
+        } else {
+            (None, Some(content.into_owned()))
+        };
+        Ok(Some(Readme {
+            path,
+            markdown,
+            text,
+        }))
+    }
+}
@@ -0,0 +1,170 @@
+//! The branches and tags of a repository.
+
+use axum::http::StatusCode;
+
+use super::{Commit, Signature};
+use crate::Args;
+
+/// How a ref's tip relates to the tip of the default branch.
+#[derive(serde::Serialize)]
+pub(crate) struct Divergence {
+    /// Commits the ref has that the default branch doesn't.
+    ahead: usize,
+    /// Commits the default branch has that the ref doesn't.
+    behind: usize,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Branch {
+    name: String,
+    /// Whether this is the branch `HEAD` points at.
+    default: bool,
+    /// The tip, which also says when the branch last moved.
+    commit: Commit,
+    /// Missing without a default branch to compare to.
+    divergence: Option<Divergence>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Branches {
+    pub default_branch: Option<String>,
+    pub branches: Vec<Branch>,
+}
+
+/// What annotated tags have on top of lightweight ones.
+#[derive(serde::Serialize)]
+pub(crate) struct Annotation {
+    id: String,
+    tagger: Option<Signature>,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Tag {
+    name: String,
+    /// The commit the tag points at, through any annotations.
+    commit: Commit,
+    annotation: Option<Annotation>,
+    divergence: Option<Divergence>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Tags {
+    pub default_branch: Option<String>,
+    pub tags: Vec<Tag>,
+}
+
+/// The short name of the branch `HEAD` points at, and the commit it's at if it
+/// has any yet.
+fn default_branch(repo: &git2::Repository) -> (Option<String>, Option<git2::Oid>) {
+    let Ok(head) = repo.find_reference("HEAD") else {
+        return (None, None);
+    };
+    let name = head
+        .symbolic_target_bytes()
+        .and_then(|target| target.strip_prefix(b"refs/heads/"))
+        .map(|name| String::from_utf8_lossy(name).into_owned());
+    let tip = head.resolve().ok().and_then(|head| head.target());
+    (name, tip)
+}
+
+fn divergence(
+    repo: &git2::Repository,
+    tip: git2::Oid,
+    default: Option<git2::Oid>,
+) -> Option<Divergence> {
+    let (ahead, behind) = repo.graph_ahead_behind(tip, default?).ok()?;
+    Some(Divergence { ahead, behind })
+}
+
+impl Branches {
+    pub(crate) async fn branches(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+    ) -> Result<Branches, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let (default_branch, default_tip) = default_branch(&repo);
+
+        let mut branches: Vec<Branch> = repo
+            .branches(Some(git2::BranchType::Local))
+            .map_err(super::internal_error)?
+            .filter_map(|branch| {
+                let (branch, _) = branch.ok()?;
+                let name = String::from_utf8_lossy(branch.name_bytes().ok()?).into_owned();
+                let commit = branch.get().peel_to_commit().ok()?;
+                Some(Branch {
+                    default: default_branch.as_ref() == Some(&name),
+                    divergence: divergence(&repo, commit.id(), default_tip),
+                    commit: Commit::from(&commit),
+                    name,
+                })
+            })
+            .collect();
+        // the default branch first, then the most recently updated.
+        branches.sort_by_key(|branch| {
+            (
+                !branch.default,
+                std::cmp::Reverse(branch.commit.committer.time),
+            )
+        });
+
+        Ok(Branches {
+            default_branch,
+            branches,
+        })
+    }
+}
+
+impl Tags {
+    pub(crate) async fn tags(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+    ) -> Result<Tags, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let (default_branch, default_tip) = default_branch(&repo);
+
+        let mut tags = vec![];
+        repo.tag_foreach(|oid, name| {
+            let name = name.strip_prefix(b"refs/tags/").unwrap_or(name);
+            let annotation = repo.find_tag(oid).ok();
+            // tags of trees and blobs don't have anything to show here.
+            let Ok(commit) = repo
+                .find_object(oid, None)
+                .and_then(|object| object.peel_to_commit())
+            else {
+                return true;
+            };
+            tags.push(Tag {
+                name: String::from_utf8_lossy(name).into_owned(),
+                divergence: divergence(&repo, commit.id(), default_tip),
+                commit: Commit::from(&commit),
+                annotation: annotation.map(|tag| Annotation {
+                    id: tag.id().to_string(),
+                    tagger: tag.tagger().map(Signature::from),
+                    message: String::from_utf8_lossy(tag.message_bytes().unwrap_or_default())
+                        .into_owned(),
+                }),
+            });
+            true
+        })
+        .map_err(super::internal_error)?;
+        // newest first, going by when they were tagged if we know.
+        tags.sort_by_key(|tag| {
+            let time = match &tag.annotation {
+                Some(Annotation {
+                    tagger: Some(tagger),
+                    ..
+                }) => tagger.time,
+                _ => tag.commit.committer.time,
+            };
+            std::cmp::Reverse(time)
+        });
+
+        Ok(Tags {
+            default_branch,
+            tags,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use axum::http::StatusCode;
+
+use crate::Args;
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct TreeReq {
+    #[serde(default)]
+    pub(crate) rev: Option<String>,
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EntryKind {
+    Blob,
+    Tree,
+    Submodule,
+    Symlink,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct TreeEntry {
+    name: String,
+    path: String,
+    kind: EntryKind,
+    /// The mode as git writes it, like `100644`.
+    mode: String,
+    id: String,
+    /// The size in bytes of blobs and symlinks.
+    size: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Tree {
+    /// The commit the revision resolved to.
+    pub commit_id: String,
+    pub path: String,
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub(crate) async fn tree(
+        args: &Args,
+        entity: &str,
+        repo_name: &str,
+        req: &TreeReq,
+    ) -> Result<Tree, StatusCode> {
+        let repo = super::open(args, entity, repo_name).await?;
+        let commit = super::find_commit(&repo, req.rev.as_deref())?;
+
+        let path = req.path.as_deref().unwrap_or_default().trim_matches('/');
+        let tree = if path.is_empty() {
+            commit.tree().unwrap()
+        } else {
+            commit
+                .tree()
+                .unwrap()
+                .get_path(Path::new(path))
+                .and_then(|entry| entry.to_object(&repo))
+                .map_err(|_| StatusCode::NOT_FOUND)?
+                .into_tree()
+                .map_err(|_| StatusCode::NOT_FOUND)?
+        };
+
+        let odb = repo.odb().unwrap();
+        let mut entries: Vec<TreeEntry> = tree
+            .iter()
+            .map(|entry| {
+                let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+                let kind = match entry.filemode() {
+                    0o040000 => EntryKind::Tree,
+                    0o160000 => EntryKind::Submodule,
+                    0o120000 => EntryKind::Symlink,
+                    _ => EntryKind::Blob,
+                };
+                // submodules point at commits in another repository.
+                let size = match kind {
+                    EntryKind::Blob | EntryKind::Symlink => {
+                        odb.read_header(entry.id()).ok().map(|(size, _)| size)
+                    }
+                    EntryKind::Tree | EntryKind::Submodule => None,
+                };
+                TreeEntry {
+                    path: match path {
+                        "" => name.clone(),
+                        path => format!("{path}/{name}"),
+                    },
+                    name,
+                    kind,
+                    mode: format!("{:06o}", entry.filemode()),
+                    id: entry.id().to_string(),
+                    size,
+                }
+            })
+            .collect();
+        // directories first, like most file browsers do.
+        entries.sort_by_key(|entry| !matches!(entry.kind, EntryKind::Tree));
+
+        Ok(Tree {
+            commit_id: commit.id().to_string(),
+            path: path.to_owned(),
+            entries,
+        })
+    }
+}
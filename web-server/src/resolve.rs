@@ -0,0 +1,224 @@
+//! Turning what clients call a repository into a path on disk. Everything that
+//! takes an entity or repository name from a client goes through here, so the
+//! name can never point outside of `<data_dir>/repositories/`.
+
+use std::path::{Path, PathBuf};
+
+use axum::http::StatusCode;
+
+/// A repository by its canonical `entity/repo` name, without any `.git` suffix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RepoName {
+    pub(crate) entity: String,
+    pub(crate) repo: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidEntity(String),
+    InvalidRepo(String),
+    NotFound,
+    /// The name resolved to something outside of the repositories, like through
+    /// a symlink.
+    OutsideDataDir(PathBuf),
+    Io(std::io::Error),
+}
+
+impl From<Error> for StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::InvalidEntity(_) | Error::InvalidRepo(_) => StatusCode::BAD_REQUEST,
+            // don't tell anyone what's outside.
+            Error::NotFound | Error::OutsideDataDir(_) => StatusCode::NOT_FOUND,
+            Error::Io(e) => {
+                eprintln!("Couldn't resolve repository: {e:?}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+/// Whether `name` can be an entity or repository name: ASCII letters, digits,
+/// `.`, `_` and `-`, starting with a letter or digit. That rules out `.`, `..`
+/// and hidden files.
+fn valid_name(name: &str) -> bool {
+    name.len() <= 100
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Check an entity name from a client.
+pub(crate) fn entity(entity: &str) -> Result<&str, Error> {
+    if valid_name(entity) {
+        Ok(entity)
+    } else {
+        Err(Error::InvalidEntity(entity.to_owned()))
+    }
+}
+
+impl RepoName {
+    /// The repository `repo` of `entity`, accepting `repo.git` for `repo`.
+    pub(crate) fn new(entity_name: &str, repo: &str) -> Result<Self, Error> {
+        let entity = entity(entity_name)?.to_owned();
+        let repo = repo.strip_suffix(".git").unwrap_or(repo);
+        if !valid_name(repo) {
+            return Err(Error::InvalidRepo(repo.to_owned()));
+        }
+        Ok(Self {
+            entity,
+            repo: repo.to_owned(),
+        })
+    }
+
+    /// Parse an `entity/repo(.git)` path as clients send it, which may start
+    /// with a `/` and end with one.
+    pub(crate) fn parse(path: &str) -> Result<Self, Error> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let path = path.strip_suffix('/').unwrap_or(path);
+        match path.split('/').collect::<Vec<_>>()[..] {
+            [entity, repo] => Self::new(entity, repo),
+            _ => Err(Error::InvalidRepo(path.to_owned())),
+        }
+    }
+
+    /// The directory of the repository, once we made sure it exists within the
+    /// repositories of `data_dir`. It's kept as either `repo` or `repo.git`.
+    pub(crate) async fn path(&self, data_dir: &Path) -> Result<PathBuf, Error> {
+        let root = canonicalize(&data_dir.join("repositories")).await?;
+        let entity = root.join(&self.entity);
+        let path = match canonicalize(&entity.join(&self.repo)).await {
+            Err(Error::NotFound) => {
+                canonicalize(&entity.join(format!("{}.git", self.repo))).await?
+            }
+            path => path?,
+        };
+        if !path.starts_with(&root) {
+            return Err(Error::OutsideDataDir(path));
+        }
+        Ok(path)
+    }
+}
+
+/// The directory of `entity` within the repositories of `data_dir`.
+pub(crate) async fn entity_path(data_dir: &Path, entity_name: &str) -> Result<PathBuf, Error> {
+    let root = canonicalize(&data_dir.join("repositories")).await?;
+    let path = canonicalize(&root.join(entity(entity_name)?)).await?;
+    if !path.starts_with(&root) {
+        return Err(Error::OutsideDataDir(path));
+    }
+    Ok(path)
+}
+
+async fn canonicalize(path: &Path) -> Result<PathBuf, Error> {
+    tokio::fs::canonicalize(path)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound,
+            _ => Error::Io(e),
+        })
+}
+
+impl std::fmt::Display for RepoName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.entity, self.repo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(entity: &str, repo: &str) -> RepoName {
+        RepoName {
+            entity: entity.to_owned(),
+            repo: repo.to_owned(),
+        }
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        for name in ["alice", "a", "0day", "my-repo", "my_repo", "v1.0", "a..b"] {
+            assert!(valid_name(name), "{name}");
+        }
+        assert!(valid_name(&"a".repeat(100)));
+    }
+
+    #[test]
+    fn rejects_names_that_go_elsewhere() {
+        for name in [
+            "", ".", "..", ".git", ".hidden", "-rf", "_x", "a/b", "/a", "a\\b", "~", "~a", "a b",
+            "a\0b", "é",
+        ] {
+            assert!(!valid_name(name), "{name:?}");
+        }
+        assert!(!valid_name(&"a".repeat(101)));
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(
+            RepoName::parse("alice/repo").unwrap(),
+            repo("alice", "repo")
+        );
+        assert_eq!(
+            RepoName::parse("/alice/repo").unwrap(),
+            repo("alice", "repo")
+        );
+        assert_eq!(
+            RepoName::parse("alice/repo/").unwrap(),
+            repo("alice", "repo")
+        );
+        assert_eq!(
+            RepoName::parse("/alice/repo.git/").unwrap(),
+            repo("alice", "repo")
+        );
+        assert_eq!(
+            RepoName::parse("alice/repo.git.git").unwrap(),
+            repo("alice", "repo.git")
+        );
+    }
+
+    #[test]
+    fn rejects_paths_that_go_elsewhere() {
+        for path in [
+            "",
+            "/",
+            "alice",
+            "alice/",
+            "../alice/repo",
+            "../repo",
+            "alice/..",
+            "alice/../repo",
+            "alice/.",
+            "alice/.git",
+            "alice/..git",
+            "//alice/repo",
+            "alice//repo",
+            "alice/repo//",
+            "a/b/c",
+            "/etc/alice/repo",
+            "~/repo",
+            "~alice/repo",
+        ] {
+            assert!(RepoName::parse(path).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn tells_entities_from_repos() {
+        assert!(matches!(
+            RepoName::parse("../repo"),
+            Err(Error::InvalidEntity(_))
+        ));
+        assert!(matches!(
+            RepoName::parse("alice/.."),
+            Err(Error::InvalidRepo(_))
+        ));
+        assert!(matches!(
+            RepoName::parse("a/b/c"),
+            Err(Error::InvalidRepo(_))
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine as _;
+use futures::TryStreamExt;
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::access::{self, Role};
+use crate::git::{self, Service, ServiceContext};
+use crate::resolve::RepoName;
+use crate::users;
+use crate::Args;
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct InfoRefsReq {
+    #[serde(default)]
+    pub(crate) service: Option<String>,
+}
+
+/// The protocol parameters the client sent through the `Git-Protocol` header.
+fn protocol_params(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get("git-protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(':').map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Ask the client to log in, which git does with the credentials it's got or by
+/// prompting for them.
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"git\"")],
+    )
+        .into_response()
+}
+
+/// The user the client logs in as with HTTP Basic authentication, if it does.
+async fn authenticate(args: &Args, headers: &HeaderMap) -> Result<Option<String>, Response> {
+    let Some(authorization) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let credentials = authorization
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| {
+            base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+        })
+        .and_then(|value| String::from_utf8(value).ok());
+    let Some((user, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return Err(unauthorized());
+    };
+    if !users::check_password(&args.data_dir, user, password).await {
+        return Err(unauthorized());
+    }
+    Ok(Some(user.to_owned()))
+}
+
+/// Open `entity/repo_name` for `service`, once we know the client may use it.
+async fn open_repo(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    service: Service,
+    headers: &HeaderMap,
+) -> Result<ServiceContext, Response> {
+    let identity = authenticate(args, headers).await?;
+    let name = RepoName::new(entity, repo_name).map_err(|e| StatusCode::from(e).into_response())?;
+    let role = Role::for_service(service);
+    match access::require(&args.data_dir, identity.as_deref(), &name, role).await {
+        Ok(()) => {}
+        // logging in may be all it takes.
+        Err(_) if identity.is_none() => return Err(unauthorized()),
+        Err(status) => return Err(status.into_response()),
+    }
+    let repo_path = name
+        .path(&args.data_dir)
+        .await
+        .map_err(|e| StatusCode::from(e).into_response())?;
+    match gix::open(&repo_path) {
+        Ok(repo) => Ok(ServiceContext {
+            repo_path,
+            repo: repo.into_sync(),
+            identity,
+            protocol: protocol_params(headers),
+            stateless: true,
+            archive_formats: args.upload_archive_formats.clone(),
+        }),
+        Err(gix::open::Error::NotARepository { .. }) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(e) => {
+            eprintln!("Couldn't open repo {name}: {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+fn body_reader(body: Body) -> impl AsyncRead + Unpin + Send {
+    StreamReader::new(body.into_data_stream().map_err(std::io::Error::other))
+}
+
+/// Serve `service` in the background, streaming whatever it writes back as the
+/// response body.
+fn stream_response(ctx: ServiceContext, service: Service, body: Body) -> Response {
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let service_name = service.name();
+    tokio::spawn(async move {
+        if let Err(e) = git::serve(&ctx, service, body_reader(body), writer).await {
+            eprintln!("{service_name} over http failed: {e:?}");
+        }
+    });
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("application/x-{service_name}-result"),
+            ),
+            (header::CACHE_CONTROL, "no-cache".to_owned()),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response()
+}
+
+/// `GET /info/refs?service=...`, the ref advertisement that starts off every
+/// smart HTTP exchange.
+pub(crate) async fn info_refs(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    req: &InfoRefsReq,
+    headers: &HeaderMap,
+) -> Result<Response, Response> {
+    // We only speak the smart protocol, dumb clients don't ask for a service.
+    let Some(service_name) = req.service.as_deref() else {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    };
+    // like git's http-backend, upload-archive is only served over ssh.
+    let service = Service::from_name(service_name)
+        .filter(|&service| service != Service::UploadArchive)
+        .ok_or(StatusCode::FORBIDDEN.into_response())?;
+    let ctx = open_repo(args, entity, repo_name, service, headers).await?;
+
+    let mut body = vec![];
+    let written = async {
+        // protocol v2 starts right away with the capabilities.
+        if !ctx.protocol_v2(service) {
+            gix_packetline::encode::text_to_write(
+                format!("# service={service_name}").as_bytes(),
+                &mut body,
+            )
+            .await?;
+            gix_packetline::encode::flush_to_write(&mut body).await?;
+        }
+        git::advertise(&ctx, service, &mut body).await
+    }
+    .await;
+    written.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("application/x-{service_name}-advertisement"),
+            ),
+            (header::CACHE_CONTROL, "no-cache".to_owned()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// `POST /git-upload-pack`, a single round of fetch negotiation.
+pub(crate) async fn upload_pack(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, Response> {
+    let ctx = open_repo(args, entity, repo_name, Service::UploadPack, headers).await?;
+    Ok(stream_response(ctx, Service::UploadPack, body))
+}
+
+/// `POST /git-receive-pack`, the commands and packfile of a push.
+pub(crate) async fn receive_pack(
+    args: &Args,
+    entity: &str,
+    repo_name: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, Response> {
+    let ctx = open_repo(args, entity, repo_name, Service::ReceivePack, headers).await?;
+    Ok(stream_response(ctx, Service::ReceivePack, body))
+}
@@ -1,19 +1,35 @@
-use gix::bstr::ByteSlice;
-use receive_pack::git_receive_pack;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use russh::{Channel, ChannelId};
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::keys::ssh_key::{EcdsaCurve, HashAlg, LineEnding};
+use russh::keys::{Algorithm, PrivateKey};
+use russh::server::Auth;
+use russh::{Channel, ChannelId, CryptoVec, MethodKind, MethodSet};
+use tokio_util::compat::TokioAsyncWriteCompatExt as _;
 
-mod util;
+use crate::access::{self, Role};
+use crate::git::{self, Service, ServiceContext};
+use crate::repositories::ArchiveFormat;
+use crate::resolve::{self, RepoName};
+use crate::users;
+use command::ExecCommand;
 
-mod receive_pack;
+mod command;
 
 pub struct SshServer {
     data_dir: PathBuf,
+    /// The formats `git archive --remote` may ask for.
+    archive_formats: Vec<ArchiveFormat>,
 }
 impl SshServer {
-    pub fn new(data_dir: PathBuf) -> Self {
-        Self { data_dir }
+    pub fn new(data_dir: PathBuf, archive_formats: Vec<ArchiveFormat>) -> Self {
+        Self {
+            data_dir,
+            archive_formats,
+        }
     }
 }
 
@@ -21,30 +37,167 @@ impl russh::server::Server for SshServer {
     type Handler = GitSshHandler;
 
     fn new_client(&mut self, _peer_addr: Option<std::net::SocketAddr>) -> Self::Handler {
-        Self::Handler::new(self.data_dir.clone())
+        Self::Handler::new(self.data_dir.clone(), self.archive_formats.clone())
     }
 }
 
+/// The host keys we serve, by the name of the file under `<data_dir>/ssh/` they're kept in.
+const HOST_KEYS: &[(&str, Algorithm)] = &[
+    ("host_ed25519", Algorithm::Ed25519),
+    (
+        "host_ecdsa",
+        Algorithm::Ecdsa {
+            curve: EcdsaCurve::NistP256,
+        },
+    ),
+    ("host_rsa", Algorithm::Rsa { hash: None }),
+];
+
+/// Load the host keys from `<data_dir>/ssh/`, generating the ones that don't exist
+/// yet so clients see the same keys across restarts.
+pub fn host_keys(data_dir: &Path) -> Vec<PrivateKey> {
+    HOST_KEYS
+        .iter()
+        .map(|(file_name, algorithm)| {
+            let path = data_dir.join("ssh").join(file_name);
+            let key = if path.exists() {
+                russh::keys::load_secret_key(&path, None).unwrap_or_else(|e| {
+                    panic!(
+                        "Failed to load host key {}. Error: {e:?}",
+                        path.to_string_lossy()
+                    )
+                })
+            } else {
+                println!("Generating host key {}...", path.to_string_lossy());
+                let key = PrivateKey::random(&mut OsRng, algorithm.clone()).unwrap();
+                key.write_openssh_file(&path, LineEnding::LF)
+                    .unwrap_or_else(|e| {
+                        panic!(
+                            "Failed to write host key {}. Error: {e:?}",
+                            path.to_string_lossy()
+                        )
+                    });
+                key
+            };
+            println!(
+                "SSH host key {} {}",
+                key.algorithm(),
+                key.public_key().fingerprint(HashAlg::Sha256)
+            );
+            key
+        })
+        .collect()
+}
+
+/// Wait for the exec request `cmd` to arrive on `channel`. Anything sent before it
+/// (like env requests) was already handled by `GitSshHandler`.
+async fn wait_for_exec(
+    channel: &mut Channel<russh::server::Msg>,
+    cmd: &[u8],
+) -> Result<(), SshHandlerErr> {
+    loop {
+        match channel.wait().await {
+            Some(russh::ChannelMsg::Exec { command, .. }) if command == cmd => return Ok(()),
+            Some(russh::ChannelMsg::Exec { .. }) => return Err(SshHandlerErr::UnexpectedCommand),
+            Some(_) => continue,
+            None => return Err(SshHandlerErr::Disconnect),
+        }
+    }
+}
+
+/// Check and open what `command` asks for on behalf of `identity`.
+async fn open_service(
+    command: Result<ExecCommand, SshHandlerErr>,
+    data_dir: &Path,
+    identity: Option<String>,
+    params: &[String],
+    archive_formats: &[ArchiveFormat],
+) -> Result<(Service, ServiceContext), SshHandlerErr> {
+    let command = command?;
+    let service = Service::from_name(&command.name).ok_or(SshHandlerErr::UnknownCommand)?;
+    let name = RepoName::parse(&command.repo_path(identity.as_deref())?)?;
+
+    let role = access::repo_role(data_dir, identity.as_deref(), &name.entity, &name.repo).await;
+    if role < Role::Read {
+        return Err(resolve::Error::NotFound.into());
+    }
+    if role < Role::for_service(service) {
+        return Err(SshHandlerErr::AccessDenied);
+    }
+
+    let repo_path = name.path(data_dir).await?;
+    let repo = gix::open(&repo_path).map_err(|e| SshHandlerErr::FailedToOpenRepo(Box::new(e)))?;
+    let ctx = ServiceContext {
+        repo_path,
+        repo: repo.into_sync(),
+        identity,
+        protocol: params.to_vec(),
+        stateless: false,
+        archive_formats: archive_formats.to_vec(),
+    };
+    Ok((service, ctx))
+}
+
+/// Serve the git command `cmd` (like `git-upload-pack '<path>'`) with `channel`
+/// as its transport. Commands we can't serve are turned down with an error the
+/// client gets to see.
+async fn git_service(
+    cmd: &[u8],
+    command: Result<ExecCommand, SshHandlerErr>,
+    data_dir: &Path,
+    identity: Option<String>,
+    params: &[String],
+    archive_formats: &[ArchiveFormat],
+    channel: &mut Channel<russh::server::Msg>,
+) -> Result<(), SshHandlerErr> {
+    wait_for_exec(channel, cmd).await?;
+
+    let mut writer = channel.make_writer();
+    let opened = open_service(command, data_dir, identity, params, archive_formats).await;
+    let (service, ctx) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            gix_packetline::encode::error_to_write(
+                e.client_message().as_bytes(),
+                (&mut writer).compat_write(),
+            )
+            .await?;
+            return Err(e);
+        }
+    };
+
+    git::advertise(&ctx, service, &mut writer).await?;
+    git::serve(&ctx, service, channel.make_reader(), writer).await?;
+    Ok(())
+}
+
 #[derive(Debug)]
 struct ChannelData {
     params: Vec<String>,
     channel: Channel<russh::server::Msg>,
 }
 pub struct GitSshHandler {
-    channel_lookup_table: std::sync::Arc<tokio::sync::Mutex<HashMap<ChannelId, ChannelData>>>,
+    /// The channels that haven't started on a command yet. Once they do, the
+    /// command has them to itself.
+    channels: HashMap<ChannelId, ChannelData>,
     data_dir: PathBuf,
+    archive_formats: Vec<ArchiveFormat>,
+    /// The user whose key the client authenticated with.
+    user: Option<String>,
 }
 
 impl GitSshHandler {
-    pub fn new(data_dir: PathBuf) -> Self {
+    pub fn new(data_dir: PathBuf, archive_formats: Vec<ArchiveFormat>) -> Self {
         Self {
-            channel_lookup_table: Default::default(),
+            channels: Default::default(),
             data_dir,
+            archive_formats,
+            user: None,
         }
     }
-    async fn add_channel(&mut self, channel_id: ChannelId, channel: Channel<russh::server::Msg>) {
-        let mut ch = self.channel_lookup_table.lock().await;
-        assert!(ch
+    fn add_channel(&mut self, channel_id: ChannelId, channel: Channel<russh::server::Msg>) {
+        assert!(self
+            .channels
             .insert(
                 channel_id,
                 ChannelData {
@@ -54,112 +207,115 @@ impl GitSshHandler {
             )
             .is_none());
     }
-    async fn remove_channel(&mut self, channel_id: ChannelId) {
-        let mut ch = self.channel_lookup_table.lock().await;
-        ch.remove_entry(&channel_id).unwrap();
+    fn remove_channel(&mut self, channel_id: ChannelId) {
+        // channels running a command were taken out already.
+        self.channels.remove(&channel_id);
     }
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum SshHandlerErr {
+    AccessDenied,
     ChannelNotFound,
     Disconnect,
-    FailedToOpenRepo(gix::open::Error),
+    FailedToOpenRepo(Box<gix::open::Error>),
+    Git(git::Error),
+    InvalidCommand(command::Error),
+    Io(std::io::Error),
+    Resolve(resolve::Error),
+    Russh(russh::Error),
     UnexpectedCommand,
     UnknownCommand,
 }
 
-impl From<russh::Error> for SshHandlerErr {
-    fn from(_value: russh::Error) -> Self {
-        match _value {
-            russh::Error::CouldNotReadKey => todo!(),
-            russh::Error::KexInit => todo!(),
-            russh::Error::UnknownAlgo => todo!(),
-            russh::Error::NoCommonAlgo { .. } => todo!(),
-            russh::Error::Version => todo!(),
-            russh::Error::Kex => todo!(),
-            russh::Error::PacketAuth => todo!(),
-            russh::Error::Inconsistent => todo!(),
-            russh::Error::NotAuthenticated => todo!(),
-            russh::Error::IndexOutOfBounds => todo!(),
-            russh::Error::UnknownKey => todo!(),
-            russh::Error::WrongServerSig => todo!(),
-            russh::Error::PacketSize(_) => todo!(),
-            russh::Error::WrongChannel => todo!(),
-            russh::Error::ChannelOpenFailure(..) => todo!(),
-            russh::Error::Disconnect => todo!(),
-            russh::Error::NoHomeDir => todo!(),
-            russh::Error::KeyChanged { .. } => todo!(),
-            russh::Error::HUP => todo!(),
-            russh::Error::ConnectionTimeout => todo!(),
-            russh::Error::KeepaliveTimeout => todo!(),
-            russh::Error::InactivityTimeout => todo!(),
-            russh::Error::NoAuthMethod => todo!(),
-            russh::Error::SendError => todo!(),
-            russh::Error::Pending => todo!(),
-            russh::Error::DecryptionError => todo!(),
-            russh::Error::RequestDenied => todo!(),
-            russh::Error::Keys(_error) => todo!(),
-            russh::Error::IO(_error) => todo!(),
-            russh::Error::Utf8(_utf8_error) => todo!(),
-            russh::Error::Compress(_compress_error) => todo!(),
-            russh::Error::Decompress(_decompress_error) => todo!(),
-            russh::Error::Join(_join_error) => todo!(),
-            russh::Error::Elapsed(_elapsed) => todo!(),
-            russh::Error::StrictKeyExchangeViolation { .. } => todo!(),
-            russh::Error::Signature(_error) => todo!(),
-            russh::Error::SshKey(_error) => todo!(),
-            russh::Error::SshEncoding(_error) => todo!(),
-            russh::Error::InvalidConfig(_) => todo!(),
+impl SshHandlerErr {
+    /// What to tell the client when we turn down its command.
+    fn client_message(&self) -> String {
+        match self {
+            SshHandlerErr::AccessDenied => "access denied".to_owned(),
+            SshHandlerErr::InvalidCommand(e) => format!("invalid command: {e}"),
+            SshHandlerErr::Resolve(
+                resolve::Error::InvalidEntity(_) | resolve::Error::InvalidRepo(_),
+            ) => "invalid repository path".to_owned(),
+            SshHandlerErr::Resolve(_) | SshHandlerErr::FailedToOpenRepo(_) => {
+                "repository not found".to_owned()
+            }
+            SshHandlerErr::UnknownCommand => "unknown command".to_owned(),
+            _ => "internal server error".to_owned(),
         }
     }
 }
 
+impl From<command::Error> for SshHandlerErr {
+    fn from(value: command::Error) -> Self {
+        Self::InvalidCommand(value)
+    }
+}
+
+impl From<resolve::Error> for SshHandlerErr {
+    fn from(value: resolve::Error) -> Self {
+        Self::Resolve(value)
+    }
+}
+
+impl From<git::Error> for SshHandlerErr {
+    fn from(value: git::Error) -> Self {
+        Self::Git(value)
+    }
+}
+
+impl From<std::io::Error> for SshHandlerErr {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<russh::Error> for SshHandlerErr {
+    fn from(value: russh::Error) -> Self {
+        Self::Russh(value)
+    }
+}
+
+/// Turn down an authentication attempt, pointing the client at public keys.
+fn reject_auth() -> Auth {
+    Auth::Reject {
+        proceed_with_methods: Some(MethodSet::from(&[MethodKind::PublicKey][..])),
+        partial_success: false,
+    }
+}
+
 impl russh::server::Handler for GitSshHandler {
     type Error = SshHandlerErr;
-    async fn auth_none(&mut self, _user: &str) -> Result<russh::server::Auth, Self::Error> {
-        Ok(russh::server::Auth::Accept)
+    async fn auth_none(&mut self, _user: &str) -> Result<Auth, Self::Error> {
+        Ok(reject_auth())
     }
-    async fn auth_password(
-        &mut self,
-        _user: &str,
-        _password: &str,
-    ) -> Result<russh::server::Auth, Self::Error> {
-        Ok(russh::server::Auth::Accept)
+    async fn auth_password(&mut self, _user: &str, _password: &str) -> Result<Auth, Self::Error> {
+        Ok(reject_auth())
     }
+    // The ssh user name doesn't matter, as with most forges everyone logs in as
+    // `git@` and is told apart by their key.
     async fn auth_publickey_offered(
         &mut self,
         _user: &str,
-        _public_key: &russh::keys::ssh_key::PublicKey,
-    ) -> Result<russh::server::Auth, Self::Error> {
-        Ok(russh::server::Auth::Accept)
-    }
-
-    async fn data(
-        &mut self,
-        _channel: russh::ChannelId,
-        data: &[u8],
-        _session: &mut russh::server::Session,
-    ) -> Result<(), Self::Error> {
-        if data == [3] {
-            return Err(SshHandlerErr::Disconnect);
+        public_key: &russh::keys::ssh_key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        match users::find_by_key(&self.data_dir, public_key).await {
+            Some(_) => Ok(Auth::Accept),
+            None => Ok(reject_auth()),
         }
-        println!("data {}", String::from_utf8_lossy(data));
-        Ok(())
     }
-    async fn extended_data(
+    async fn auth_publickey(
         &mut self,
-        _channel: russh::ChannelId,
-        _code: u32,
-        data: &[u8],
-        _session: &mut russh::server::Session,
-    ) -> Result<(), Self::Error> {
-        if data == [3] {
-            return Err(SshHandlerErr::Disconnect);
-        }
-        println!("extended data: {}", String::from_utf8_lossy(data));
-        Ok(())
+        _user: &str,
+        public_key: &russh::keys::ssh_key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        let Some(user) = users::find_by_key(&self.data_dir, public_key).await else {
+            return Ok(reject_auth());
+        };
+        println!("authenticated as {user}");
+        self.user = Some(user);
+        Ok(Auth::Accept)
     }
 
     async fn env_request(
@@ -170,8 +326,8 @@ impl russh::server::Handler for GitSshHandler {
         session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
         println!("env request...");
-        let mut channel_lookup_table = self.channel_lookup_table.lock().await;
-        let channel_data = channel_lookup_table
+        let channel_data = self
+            .channels
             .get_mut(&channel)
             .ok_or(SshHandlerErr::ChannelNotFound)?;
         println!("{variable_name} is wanting to be set to {variable_value}");
@@ -181,7 +337,7 @@ impl russh::server::Handler for GitSshHandler {
                 .params
                 .extend(variable_value.split(':').map(String::from));
         }
-        session.handle().channel_success(channel).await.unwrap();
+        session.channel_success(channel)?;
         Ok(())
     }
 
@@ -191,7 +347,7 @@ impl russh::server::Handler for GitSshHandler {
         _session: &mut russh::server::Session,
     ) -> Result<bool, Self::Error> {
         println!("channel_open_session...");
-        self.add_channel(channel.id(), channel).await;
+        self.add_channel(channel.id(), channel);
         Ok(true)
     }
     async fn channel_close(
@@ -200,7 +356,7 @@ impl russh::server::Handler for GitSshHandler {
         _session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
         println!("removing channel...");
-        self.remove_channel(channel).await;
+        self.remove_channel(channel);
         Ok(())
     }
 
@@ -208,33 +364,60 @@ impl russh::server::Handler for GitSshHandler {
         &mut self,
         channel_id: russh::ChannelId,
         cmd: &[u8],
-        _session: &mut russh::server::Session,
+        session: &mut russh::server::Session,
     ) -> Result<(), Self::Error> {
         println!("{}", String::from_utf8_lossy(cmd));
-        const VALID_CMDS: &[&str] = &["git-receive-pack", "git-upload-pack"];
-        let Some(cmd_name) = VALID_CMDS
-            .iter()
-            .copied()
-            .find(|potential_cmd| cmd.trim().starts_with(potential_cmd.as_bytes()))
-        else {
-            return Err(SshHandlerErr::UnexpectedCommand);
-        };
+        const VALID_CMDS: &[&str] = &["git-receive-pack", "git-upload-archive", "git-upload-pack"];
+        // Whatever is wrong with the command is reported to the client once the
+        // channel is ready for it.
+        let command = command::parse(cmd)
+            .map_err(SshHandlerErr::from)
+            .and_then(|command| {
+                if VALID_CMDS.contains(&command.name.as_str()) {
+                    Ok(command)
+                } else {
+                    Err(SshHandlerErr::UnknownCommand)
+                }
+            });
         let data_dir = self.data_dir.clone();
-        let lookup_table = Arc::clone(&self.channel_lookup_table);
+        let identity = self.user.clone();
+        let archive_formats = self.archive_formats.clone();
         let cmd = Vec::from(cmd);
+        let Some(ChannelData {
+            params,
+            mut channel,
+        }) = self.channels.remove(&channel_id)
+        else {
+            // like a second command on a channel that's already running one.
+            let message = CryptoVec::from_slice(b"channel is already in use\n");
+            session.extended_data(channel_id, 1, message)?;
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        };
+        session.channel_success(channel_id)?;
 
         tokio::spawn(async move {
-            let mut lookup_table = lookup_table.lock().await;
-            let Some(ChannelData { params: _, channel }) = lookup_table.get_mut(&channel_id) else {
-                panic!("Failed to get channel with channel id {channel_id}");
+            let result = git_service(
+                &cmd,
+                command,
+                &data_dir,
+                identity,
+                &params,
+                &archive_formats,
+                &mut channel,
+            )
+            .await;
+            let exit_status = match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{} failed: {e:?}", String::from_utf8_lossy(&cmd));
+                    1
+                }
             };
-            match cmd_name {
-                "git-receive-pack" => git_receive_pack(cmd, data_dir, channel)
-                    .await
-                    .expect("git recv pack failed..."),
-                _ => panic!("unknown command {cmd_name}"),
-            }
-            channel.close().await.unwrap();
+            // the client may already be gone, in which case there's nobody left to tell.
+            let _ = channel.exit_status(exit_status).await;
+            let _ = channel.eof().await;
+            let _ = channel.close().await;
         });
         Ok(())
     }
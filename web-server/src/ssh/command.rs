@@ -0,0 +1,243 @@
+//! Parsing the commands git clients exec over SSH, like
+//! `git-upload-pack '/entity/repo.git'`. Clients quote the path for a POSIX
+//! shell, so that's what we undo here.
+
+use std::fmt;
+
+/// A git command from an exec request.
+#[derive(Debug)]
+pub(crate) struct ExecCommand {
+    pub(crate) name: String,
+    path: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUtf8,
+    UnterminatedQuote,
+    TrailingBackslash,
+    /// Git commands take exactly one argument, the repository.
+    WrongArgumentCount(usize),
+    /// `~/repo` only makes sense once we know who's asking.
+    NoHomeEntity,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUtf8 => write!(f, "command is not valid UTF-8"),
+            Error::UnterminatedQuote => write!(f, "unterminated quote"),
+            Error::TrailingBackslash => write!(f, "trailing backslash"),
+            Error::WrongArgumentCount(n) => write!(f, "expected a repository, got {n} arguments"),
+            Error::NoHomeEntity => write!(f, "~/ needs an authenticated user"),
+        }
+    }
+}
+
+/// Split `cmd` into words like a POSIX shell would: whitespace separates words,
+/// single quotes keep everything as is, and a backslash escapes the next
+/// character, which within double quotes only goes for `"`, `\`, `$` and `` ` ``.
+fn split_words(cmd: &str) -> Result<Vec<String>, Error> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or(Error::UnterminatedQuote)? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or(Error::UnterminatedQuote)? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or(Error::UnterminatedQuote)? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            '\n' => {}
+                            c => word.extend(['\\', c]),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars.next().ok_or(Error::TrailingBackslash)?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Parse the exec request `cmd` into the command and the repository it's for.
+pub(crate) fn parse(cmd: &[u8]) -> Result<ExecCommand, Error> {
+    let cmd = std::str::from_utf8(cmd).map_err(|_| Error::InvalidUtf8)?;
+    match <[String; 2]>::try_from(split_words(cmd)?) {
+        Ok([name, path]) => Ok(ExecCommand { name, path }),
+        Err(words) => Err(Error::WrongArgumentCount(words.len().saturating_sub(1))),
+    }
+}
+
+impl ExecCommand {
+    /// The repository path, with `~entity/` expanded to `entity/` and `~/` to
+    /// the entity of the user `identity`.
+    pub(crate) fn repo_path(&self, identity: Option<&str>) -> Result<String, Error> {
+        let path = self.path.strip_prefix('/').unwrap_or(&self.path);
+        match path.strip_prefix('~') {
+            Some(rest) => match rest.strip_prefix('/') {
+                Some(repo) => Ok(format!("{}/{repo}", identity.ok_or(Error::NoHomeEntity)?)),
+                None => Ok(rest.to_owned()),
+            },
+            None => Ok(path.to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve::RepoName;
+
+    fn words(cmd: &str) -> Vec<String> {
+        split_words(cmd).unwrap()
+    }
+
+    fn repo_path(cmd: &str, identity: Option<&str>) -> Result<String, Error> {
+        parse(cmd.as_bytes())?.repo_path(identity)
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            words("  git-upload-pack \t alice/repo  "),
+            ["git-upload-pack", "alice/repo"]
+        );
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn undoes_quoting() {
+        assert_eq!(words("'a b'"), ["a b"]);
+        assert_eq!(words(r#""a b""#), ["a b"]);
+        assert_eq!(words(r#"'a'"b"c"#), ["abc"]);
+        assert_eq!(words(r#"'it'\''s'"#), ["it's"]);
+        assert_eq!(words(r#""say \"hi\"""#), [r#"say "hi""#]);
+        assert_eq!(words(r#"'"' "'""#), [r#"""#, "'"]);
+        assert_eq!(words("''"), [""]);
+    }
+
+    #[test]
+    fn undoes_escapes() {
+        assert_eq!(words(r"a\ b"), ["a b"]);
+        assert_eq!(words(r"\'a\'"), ["'a'"]);
+        // single quotes keep backslashes, double quotes only some of them.
+        assert_eq!(words(r"'a\b'"), [r"a\b"]);
+        assert_eq!(words(r#""a\b\\c\$""#), [r"a\b\c$"]);
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert!(matches!(split_words("'abc"), Err(Error::UnterminatedQuote)));
+        assert!(matches!(
+            split_words(r#""abc"#),
+            Err(Error::UnterminatedQuote)
+        ));
+        assert!(matches!(
+            split_words(r#""abc\"#),
+            Err(Error::UnterminatedQuote)
+        ));
+        assert!(matches!(
+            split_words(r#"'a'"b"#),
+            Err(Error::UnterminatedQuote)
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_backslash() {
+        assert!(matches!(
+            split_words(r"abc\"),
+            Err(Error::TrailingBackslash)
+        ));
+        assert!(matches!(
+            split_words(r"'a' \"),
+            Err(Error::TrailingBackslash)
+        ));
+    }
+
+    #[test]
+    fn takes_exactly_one_argument() {
+        assert!(matches!(
+            parse(b"git-upload-pack"),
+            Err(Error::WrongArgumentCount(0))
+        ));
+        assert!(matches!(
+            parse(b"git-upload-pack a b"),
+            Err(Error::WrongArgumentCount(2))
+        ));
+        assert!(matches!(
+            parse(b"git-upload-pack '\xff'"),
+            Err(Error::InvalidUtf8)
+        ));
+        assert_eq!(
+            parse(b"git-upload-pack 'a b'").unwrap().name,
+            "git-upload-pack"
+        );
+    }
+
+    #[test]
+    fn strips_leading_slash() {
+        assert_eq!(
+            repo_path("git-upload-pack '/alice/repo.git'", None).unwrap(),
+            "alice/repo.git"
+        );
+        assert_eq!(
+            repo_path("git-upload-pack alice/repo", None).unwrap(),
+            "alice/repo"
+        );
+    }
+
+    #[test]
+    fn expands_home() {
+        let home = "git-upload-pack '~/repo.git'";
+        assert_eq!(repo_path(home, Some("bob")).unwrap(), "bob/repo.git");
+        assert!(matches!(repo_path(home, None), Err(Error::NoHomeEntity)));
+        assert_eq!(
+            repo_path("git-upload-pack '~alice/repo'", None).unwrap(),
+            "alice/repo"
+        );
+        assert_eq!(
+            repo_path("git-upload-pack '/~alice/repo'", Some("bob")).unwrap(),
+            "alice/repo"
+        );
+    }
+
+    #[test]
+    fn paths_stay_within_repositories() {
+        for path in [
+            "'/../alice/repo'",
+            "'alice/../../etc'",
+            "'///etc/passwd'",
+            "'~/../alice/repo'",
+            "'~../alice'",
+            "'~/'",
+            "'~'",
+            "'alice/.git'",
+        ] {
+            let cmd = format!("git-upload-pack {path}");
+            let parsed = repo_path(&cmd, Some("bob")).map(|path| RepoName::parse(&path));
+            assert!(
+                !matches!(parsed, Ok(Ok(_))),
+                "{path} resolved to {parsed:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Users of the forge and how they log in. Every user has a directory under
+//! `<data_dir>/users/` holding an OpenSSH `authorized_keys` file, and for HTTP a
+//! `password` file with the argon2 hash of their password as a PHC string (like
+//! `argon2 <salt> -id -e` prints).
+
+use std::path::Path;
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use russh::keys::ssh_key::{AuthorizedKeys, HashAlg, PublicKey};
+
+use crate::get_entries;
+use crate::resolve;
+
+/// Find the user `key` belongs to, matching it by fingerprint against the keys
+/// every user authorized.
+pub(crate) async fn find_by_key(data_dir: &Path, key: &PublicKey) -> Option<String> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+    for user in get_entries(&data_dir.join("users/")).await {
+        let user = user.to_string_lossy().into_owned();
+        let path = data_dir.join("users").join(&user).join("authorized_keys");
+        let Ok(authorized_keys) = tokio::fs::read_to_string(&path).await else {
+            continue;
+        };
+        for entry in AuthorizedKeys::new(&authorized_keys) {
+            match entry {
+                Ok(entry) if entry.public_key().fingerprint(HashAlg::Sha256) == fingerprint => {
+                    return Some(user);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("WARNING: invalid key in {}: {e}", path.to_string_lossy()),
+            }
+        }
+    }
+    None
+}
+
+/// Check `password` against the hash `user` keeps. Users without a `password`
+/// file can't log in with one.
+pub(crate) async fn check_password(data_dir: &Path, user: &str, password: &str) -> bool {
+    if resolve::entity(user).is_err() {
+        return false;
+    }
+    let path = data_dir.join("users").join(user).join("password");
+    let Ok(hash) = tokio::fs::read_to_string(&path).await else {
+        return false;
+    };
+    let password = password.to_owned();
+    // hashing takes a while on purpose, which the runtime shouldn't wait on.
+    tokio::task::spawn_blocking(move || match PasswordHash::new(hash.trim()) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            eprintln!(
+                "WARNING: invalid password hash in {}: {e}",
+                path.to_string_lossy()
+            );
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}